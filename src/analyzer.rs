@@ -0,0 +1,373 @@
+//! Traces of the four lines recorded with a logic analyzer, as VCD or sigrok CSV.
+//!
+//! The lines are sampled whenever the clock changes, like `receive_b15` does, so the result is
+//! the nibble stream a receiver would have seen and can go through the dissector.
+//! Channels are found by name, `--channels` style: D0, D1, D2 and CLK, where CLK falls back to
+//! D3 as sigrok names the fourth channel. A name can be qualified by its VCD scope, e.g.
+//! `received.D0` in a file written by `v7 vcd`.
+
+use std::io;
+use std::time::Duration;
+
+use crate::vcd::Trace;
+
+/// Names of the data lines and the clock, in the order of their bits in a nibble
+pub const DEFAULT_CHANNELS: [&str; 4] = ["D0", "D1", "D2", "CLK"];
+
+/// Another name the line is looked up by if the first one is missing
+fn fallback(channel: &str) -> Option<&'static str> {
+    (channel == "CLK").then_some("D3")
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Time of a sample, negative, NaN or too long a time is an error
+fn at(seconds: f64) -> io::Result<Duration> {
+    Duration::try_from_secs_f64(seconds).map_err(|_| invalid(format!("bad time {seconds} s")))
+}
+
+/// Whether `text` looks like a VCD rather than a CSV
+pub fn is_vcd(text: &str) -> bool {
+    text.trim_start().starts_with('$')
+}
+
+/// Keeps the samples where the clock changed, the nibble holds the lines at that moment
+struct Sampler {
+    samples: Vec<(Duration, u8)>,
+}
+
+impl Sampler {
+    fn sample(&mut self, at: Duration, nibble: u8) {
+        if self
+            .samples
+            .last()
+            .is_none_or(|&(_, last)| (last ^ nibble) & 0b1000 != 0)
+        {
+            self.samples.push((at, nibble));
+        }
+    }
+}
+
+/// Length of a unit like `us` or `1 ns` in a timescale or a column header, in seconds
+fn unit(unit: &str) -> Option<f64> {
+    let unit = unit.trim();
+    let digits = unit
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(unit.len());
+    let factor: f64 = match &unit[..digits] {
+        "" => 1.0,
+        n => n.parse().ok()?,
+    };
+    let base = match unit[digits..].trim() {
+        "s" => 1.0,
+        "ms" => 1e-3,
+        "us" | "µs" => 1e-6,
+        "ns" => 1e-9,
+        "ps" => 1e-12,
+        "fs" => 1e-15,
+        _ => return None,
+    };
+    Some(base * factor)
+}
+
+/// Reads the lines named in `channels` (D0, D1, D2, CLK) from a VCD
+pub fn read_vcd(text: &str, channels: &[&str; 4]) -> io::Result<Trace> {
+    let mut tokens = text.split_whitespace();
+    let mut timescale = 1e-9;
+    let mut scopes: Vec<&str> = Vec::new();
+    // identifier and qualified name of every 1 bit wire
+    let mut vars: Vec<(&str, String)> = Vec::new();
+
+    while let Some(token) = tokens.next() {
+        let body: Vec<&str> = tokens.by_ref().take_while(|&t| t != "$end").collect();
+        match token {
+            "$timescale" => {
+                timescale = unit(&body.concat())
+                    .ok_or_else(|| invalid(format!("unknown timescale {}", body.concat())))?;
+            }
+            "$scope" => scopes.push(body.get(1).copied().unwrap_or("")),
+            "$upscope" => {
+                scopes.pop();
+            }
+            "$var" if body.len() >= 4 && body[1] == "1" => {
+                let mut name = scopes.join(".");
+                name.push('.');
+                name.push_str(body[3]);
+                vars.push((body[2], name));
+            }
+            "$enddefinitions" => break,
+            _ => {}
+        }
+    }
+
+    // identifier of every line, the first scope with a matching name wins
+    let find = |channel: &str| {
+        vars.iter()
+            .find(|(_, name)| name == channel || name.ends_with(&format!(".{channel}")))
+            .map(|(id, _)| *id)
+    };
+    let mut lines = Vec::new();
+    for channel in channels {
+        let id = find(channel)
+            .or_else(|| fallback(channel).and_then(find))
+            .ok_or_else(|| invalid(format!("no channel {channel} in the VCD")))?;
+        lines.push(id);
+    }
+
+    let mut sampler = Sampler {
+        samples: Vec::new(),
+    };
+    let mut nibble = 0u8;
+    let mut now: Option<u64> = None;
+    while let Some(token) = tokens.next() {
+        if token == "$comment" {
+            tokens.by_ref().find(|&t| t == "$end");
+            continue;
+        }
+        if let Some(time) = token.strip_prefix('#') {
+            if let Some(now) = now {
+                sampler.sample(at(timescale * now as f64)?, nibble);
+            }
+            now = Some(
+                time.parse()
+                    .map_err(|_| invalid(format!("bad timestamp {token}")))?,
+            );
+            continue;
+        }
+        let (value, id) = match token.as_bytes().first() {
+            Some(b'0' | b'1' | b'x' | b'X' | b'z' | b'Z') => token.split_at(1),
+            // vectors, reals and the keywords around `$dumpvars` are not the lines
+            _ => continue,
+        };
+        if let Some(line) = lines.iter().position(|&line| line == id) {
+            match value {
+                "1" => nibble |= 1 << line,
+                _ => nibble &= !(1 << line),
+            }
+        }
+    }
+    if let Some(now) = now {
+        sampler.sample(at(timescale * now as f64)?, nibble);
+    }
+
+    Ok(Trace {
+        name: "analyzer".to_string(),
+        samples: sampler.samples,
+    })
+}
+
+/// Reads the lines named in `channels` (D0, D1, D2, CLK) from a sigrok CSV export,
+/// with a `Time [unit]` column or the samplerate of the header comments for the timing
+pub fn read_sigrok_csv(text: &str, channels: &[&str; 4]) -> io::Result<Trace> {
+    let mut sample_period = 0.0;
+    let mut rows = text.lines().map(str::trim).filter(|line| !line.is_empty());
+
+    let header = loop {
+        let Some(line) = rows.next() else {
+            return Err(invalid("no header row in the CSV".to_string()));
+        };
+        let Some(comment) = line.strip_prefix(';') else {
+            break line;
+        };
+        // e.g. `; Samplerate: 1 MHz`
+        if let Some(rate) = comment.trim().strip_prefix("Samplerate:") {
+            let rate = rate.trim();
+            let digits = rate.find(' ').unwrap_or(rate.len());
+            let hz: f64 = rate[..digits].parse().unwrap_or(0.0);
+            let hz = hz
+                * match rate[digits..].trim() {
+                    "kHz" => 1e3,
+                    "MHz" => 1e6,
+                    "GHz" => 1e9,
+                    _ => 1.0,
+                };
+            if hz > 0.0 {
+                sample_period = 1.0 / hz;
+            }
+        }
+    };
+
+    let columns: Vec<&str> = header.split(',').map(str::trim).collect();
+    // `Time [us]`
+    let time = columns
+        .first()
+        .filter(|column| column.starts_with("Time"))
+        .map(|column| {
+            let unit_name = column
+                .split_once('[')
+                .map_or("s", |(_, unit)| unit.trim_end_matches(']'));
+            unit(unit_name).ok_or_else(|| invalid(format!("unknown time unit in {column}")))
+        })
+        .transpose()?;
+    let find = |channel: &str| columns.iter().position(|&column| column == channel);
+    let mut lines = Vec::new();
+    for channel in channels {
+        let column = find(channel)
+            .or_else(|| fallback(channel).and_then(find))
+            .ok_or_else(|| invalid(format!("no channel {channel} in the CSV")))?;
+        lines.push(column);
+    }
+
+    let mut sampler = Sampler {
+        samples: Vec::new(),
+    };
+    for (i, row) in rows.filter(|row| !row.starts_with(';')).enumerate() {
+        let fields: Vec<&str> = row.split(',').map(str::trim).collect();
+        let time = match time {
+            Some(unit) => {
+                let value: f64 = fields[0]
+                    .parse()
+                    .map_err(|_| invalid(format!("bad time in row {row}")))?;
+                at(unit * value)?
+            }
+            None => at(sample_period * i as f64)?,
+        };
+        let mut nibble = 0;
+        for (bit, &column) in lines.iter().enumerate() {
+            match fields.get(column) {
+                Some(&"1") => nibble |= 1 << bit,
+                Some(_) => {}
+                None => return Err(invalid(format!("short row {row}"))),
+            }
+        }
+        sampler.sample(time, nibble);
+    }
+
+    Ok(Trace {
+        name: "analyzer".to_string(),
+        samples: sampler.samples,
+    })
+}
+
+/// Reads a VCD or sigrok CSV trace, whichever `text` is
+pub fn read_trace(text: &str, channels: &[&str; 4]) -> io::Result<Trace> {
+    if is_vcd(text) {
+        read_vcd(text, channels)
+    } else {
+        read_sigrok_csv(text, channels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Both directions as `v7 vcd` writes them, the received lines change between clock edges
+    const VCD: &str = "\
+$timescale 1 us $end
+$scope module sent $end
+$var wire 1 a D0 $end
+$var wire 1 b D1 $end
+$var wire 1 c D2 $end
+$var wire 1 d CLK $end
+$upscope $end
+$scope module received $end
+$var wire 1 e D0 $end
+$var wire 1 f D1 $end
+$var wire 1 g D2 $end
+$var wire 1 h CLK $end
+$var wire 8 i control $end
+$upscope $end
+$enddefinitions $end
+#0
+$dumpvars
+0a 0b 0c 0d 0e 0f 0g 0h b0 i
+$end
+#10
+1e 1h
+#15
+1f
+$comment 1g $end
+#20
+0h
+#30
+1a 1d b111 i
+#40
+1h
+";
+
+    const RECEIVED: [&str; 4] = ["received.D0", "received.D1", "received.D2", "received.CLK"];
+
+    fn us(samples: &[(u64, u8)]) -> Vec<(Duration, u8)> {
+        samples
+            .iter()
+            .map(|&(us, nibble)| (Duration::from_micros(us), nibble))
+            .collect()
+    }
+
+    #[test]
+    fn vcd_is_sampled_on_clock_edges() {
+        let trace = read_trace(VCD, &RECEIVED).unwrap();
+        assert_eq!(
+            trace.samples,
+            us(&[(0, 0), (10, 0b1001), (20, 0b0011), (40, 0b1011)])
+        );
+    }
+
+    #[test]
+    fn vcd_names_match_in_the_first_scope() {
+        let trace = read_vcd(VCD, &DEFAULT_CHANNELS).unwrap();
+        assert_eq!(trace.samples, us(&[(0, 0), (30, 0b1001)]));
+    }
+
+    #[test]
+    fn vcd_clock_falls_back_to_d3() {
+        let vcd = "\
+$timescale 1 ns $end
+$var wire 1 ! D0 $end
+$var wire 1 \" D1 $end
+$var wire 1 # D2 $end
+$var wire 1 $ D3 $end
+$enddefinitions $end
+#0
+$dumpvars 0! 0\" 0# 0$ $end
+#1000
+1! 1$
+";
+        let trace = read_trace(vcd, &DEFAULT_CHANNELS).unwrap();
+        assert_eq!(trace.samples, us(&[(0, 0), (1, 0b1001)]));
+
+        let error = read_vcd(vcd, &["D0", "D1", "D2", "D4"]).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn csv_with_time_column() {
+        let csv = "\
+; Channels (4/8)
+; Samplerate: 1 MHz
+Time [us],D0,D1,D2,D3
+0,0,0,0,0
+1,1,0,0,1
+2,1,1,0,1
+3,1,1,0,0
+";
+        let trace = read_trace(csv, &DEFAULT_CHANNELS).unwrap();
+        assert_eq!(trace.samples, us(&[(0, 0), (1, 0b1001), (3, 0b0011)]));
+    }
+
+    #[test]
+    fn csv_timed_by_samplerate() {
+        let csv = "\
+; Samplerate: 2 kHz
+D0,D1,D2,CLK
+0,0,0,0
+1,0,0,1
+1,0,0,1
+0,1,0,0
+";
+        let trace = read_trace(csv, &DEFAULT_CHANNELS).unwrap();
+        assert_eq!(trace.samples, us(&[(0, 0), (500, 0b1001), (1500, 0b0010)]));
+    }
+
+    #[test]
+    fn csv_rejects_times_out_of_range() {
+        for time in ["-1", "NaN", "inf", "1e300"] {
+            let csv = format!("Time [s],D0,D1,D2,D3\n{time},0,0,0,0\n");
+            let error = read_sigrok_csv(&csv, &DEFAULT_CHANNELS).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{time}");
+        }
+    }
+}
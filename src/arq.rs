@@ -0,0 +1,289 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::clock::Timer;
+
+/// Tuning of the enquiry retransmission
+#[derive(Debug, Clone, Copy)]
+pub struct ArqConfig {
+    /// Timeout before the first round trip has been measured
+    pub initial_rto: Duration,
+    pub min_rto: Duration,
+    pub max_rto: Duration,
+    /// Timeouts in a row after which the transfer is given up
+    pub max_retries: u32,
+}
+
+impl Default for ArqConfig {
+    fn default() -> Self {
+        Self {
+            initial_rto: Duration::from_millis(2000),
+            min_rto: Duration::from_millis(500),
+            max_rto: Duration::from_secs(120),
+            max_retries: 8,
+        }
+    }
+}
+
+/// The peer did not answer after `retries` retransmissions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetriesExhausted {
+    pub retries: u32,
+}
+
+impl fmt::Display for RetriesExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no response after {} retransmissions", self.retries)
+    }
+}
+
+impl std::error::Error for RetriesExhausted {}
+
+/// Retransmission timeout estimated from measured round trips (RFC 6298):
+/// smoothed RTT plus four times its variation, doubled on every timeout.
+///
+/// A round trip is the time from our frame being fully sent until its answer has been decoded,
+/// answers to a frame sent more than once are not sampled (Karn). The deadline is fixed once
+/// armed, whatever else the peer sends meanwhile doesn't move it. Only while a frame of the
+/// peer arrives the clock is held, the peer can't answer before it is through.
+#[derive(Debug, Clone)]
+pub struct RetransmitTimer {
+    config: ArqConfig,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+    retries: u32,
+    timer: Timer,
+    /// When the frame being timed went out, `None` once sampled or after a retransmission (Karn)
+    sent_at: Option<Instant>,
+    /// Held since, see [`RetransmitTimer::pause`]
+    paused_at: Option<Instant>,
+}
+
+impl RetransmitTimer {
+    pub fn new(config: ArqConfig) -> Self {
+        Self {
+            config,
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: config.initial_rto,
+            retries: 0,
+            timer: Timer::new(),
+            sent_at: None,
+            paused_at: None,
+        }
+    }
+
+    /// Current timeout including backoff
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    pub fn is_armed(&self) -> bool {
+        self.timer.is_running()
+    }
+
+    /// Starts timing, `at` is when the last nibble of the frame went out
+    pub fn arm(&mut self, at: Instant) {
+        if self.retries == 0 {
+            self.sent_at = Some(at);
+        }
+        if let Some(paused_at) = &mut self.paused_at {
+            *paused_at = (*paused_at).max(at);
+        }
+        self.timer.start(at, self.rto);
+    }
+
+    /// A frame of the peer started arriving at `now`, the time until
+    /// [`RetransmitTimer::resume`] counts neither towards the timeout nor the round trip
+    pub fn pause(&mut self, now: Instant) {
+        self.paused_at.get_or_insert(now);
+    }
+
+    /// The peer's frame is through
+    pub fn resume(&mut self, now: Instant) {
+        let Some(paused_at) = self.paused_at.take() else {
+            return;
+        };
+        let held = now.saturating_duration_since(paused_at);
+        self.timer.extend(held);
+        if let Some(sent_at) = &mut self.sent_at {
+            *sent_at += held;
+        }
+    }
+
+    /// The answer to the frame being timed was decoded at `now`: take an RTT sample
+    /// unless the frame went out more than once, stop timing and forget the backoff
+    pub fn on_response(&mut self, now: Instant) {
+        if let Some(sent_at) = self.sent_at.take() {
+            self.sample(now.saturating_duration_since(sent_at));
+        }
+        self.cancel();
+    }
+
+    /// Nothing to wait for anymore, stops without a sample
+    pub fn cancel(&mut self) {
+        self.timer.stop();
+        self.sent_at = None;
+        if self.retries > 0 {
+            self.retries = 0;
+            self.rto = self.computed_rto();
+        }
+    }
+
+    /// Another copy of the frame went out before its answer arrived,
+    /// an answer can't be told apart anymore and is not sampled (Karn)
+    pub fn on_retransmit(&mut self) {
+        self.sent_at = None;
+    }
+
+    pub fn expired(&self, now: Instant) -> bool {
+        self.paused_at.is_none() && self.timer.expired(now)
+    }
+
+    /// Registers a timeout: backs off and disarms until the retransmission is sent
+    pub fn on_timeout(&mut self) -> Result<(), RetriesExhausted> {
+        self.timer.stop();
+        self.sent_at = None;
+        self.retries += 1;
+        if self.retries > self.config.max_retries {
+            return Err(RetriesExhausted {
+                retries: self.config.max_retries,
+            });
+        }
+        self.rto = (self.rto * 2).min(self.config.max_rto);
+        Ok(())
+    }
+
+    fn sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                self.rttvar = self.rttvar * 3 / 4 + srtt.abs_diff(rtt) / 4;
+                self.srtt = Some(srtt * 7 / 8 + rtt / 8);
+            }
+        }
+        self.rto = self.computed_rto();
+    }
+
+    fn computed_rto(&self) -> Duration {
+        match self.srtt {
+            Some(srtt) => (srtt + self.rttvar * 4).clamp(self.config.min_rto, self.config.max_rto),
+            None => self.config.initial_rto,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, VirtualClock};
+
+    const CONFIG: ArqConfig = ArqConfig {
+        initial_rto: Duration::from_millis(1000),
+        min_rto: Duration::from_millis(200),
+        max_rto: Duration::from_millis(5000),
+        max_retries: 3,
+    };
+
+    #[test]
+    fn expires_after_rto_of_virtual_time() {
+        let clock = VirtualClock::new();
+        let mut timer = RetransmitTimer::new(CONFIG);
+        assert!(!timer.expired(clock.now()));
+
+        timer.arm(clock.now());
+        clock.advance(Duration::from_millis(999));
+        assert!(!timer.expired(clock.now()));
+        clock.advance(Duration::from_millis(1));
+        assert!(timer.expired(clock.now()));
+    }
+
+    #[test]
+    fn backs_off_until_retries_run_out() {
+        let clock = VirtualClock::new();
+        let mut timer = RetransmitTimer::new(CONFIG);
+        for (retry, rto) in [(1, 2000), (2, 4000), (3, 5000)] {
+            timer.arm(clock.now());
+            clock.advance(timer.rto());
+            assert!(timer.expired(clock.now()));
+            timer.on_timeout().unwrap();
+            assert_eq!(timer.retries(), retry);
+            assert_eq!(timer.rto(), Duration::from_millis(rto));
+            assert!(!timer.is_armed());
+        }
+        timer.arm(clock.now());
+        clock.advance(timer.rto());
+        assert_eq!(timer.on_timeout(), Err(RetriesExhausted { retries: 3 }));
+    }
+
+    #[test]
+    fn response_forgets_the_backoff() {
+        let clock = VirtualClock::new();
+        let mut timer = RetransmitTimer::new(CONFIG);
+        timer.arm(clock.now());
+        clock.advance(timer.rto());
+        timer.on_timeout().unwrap();
+        timer.arm(clock.now());
+        clock.advance(Duration::from_millis(300));
+        timer.on_response(clock.now());
+        assert!(!timer.is_armed());
+        assert_eq!(timer.retries(), 0);
+        assert_eq!(timer.rto(), CONFIG.initial_rto);
+        // the answer may belong to either copy (Karn)
+        assert_eq!(timer.srtt(), None);
+    }
+
+    #[test]
+    fn samples_the_round_trip_to_the_answer() {
+        let clock = VirtualClock::new();
+        let mut timer = RetransmitTimer::new(CONFIG);
+        timer.arm(clock.now());
+        clock.advance(Duration::from_millis(400));
+        timer.on_response(clock.now());
+        assert_eq!(timer.srtt(), Some(Duration::from_millis(400)));
+        // srtt + 4 * rttvar
+        assert_eq!(timer.rto(), Duration::from_millis(1200));
+
+        timer.arm(clock.now());
+        clock.advance(Duration::from_millis(100));
+        timer.on_retransmit();
+        clock.advance(Duration::from_millis(100));
+        timer.on_response(clock.now());
+        assert_eq!(timer.srtt(), Some(Duration::from_millis(400)));
+    }
+
+    #[test]
+    fn peer_frame_is_held_out() {
+        let clock = VirtualClock::new();
+        let mut timer = RetransmitTimer::new(CONFIG);
+        timer.arm(clock.now());
+        clock.advance(Duration::from_millis(600));
+        timer.pause(clock.now());
+        clock.advance(Duration::from_millis(3000));
+        assert!(!timer.expired(clock.now()));
+        timer.resume(clock.now());
+        assert!(!timer.expired(clock.now()));
+        clock.advance(Duration::from_millis(400));
+        assert!(timer.expired(clock.now()));
+
+        timer.arm(clock.now());
+        timer.pause(clock.now());
+        clock.advance(Duration::from_millis(2000));
+        timer.resume(clock.now());
+        clock.advance(Duration::from_millis(300));
+        timer.on_response(clock.now());
+        assert_eq!(timer.srtt(), Some(Duration::from_millis(300)));
+    }
+}
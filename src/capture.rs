@@ -0,0 +1,318 @@
+//! Wire captures: every nibble sent and received on a [`Link`] with its direction and a
+//! monotonic timestamp, so a failed transfer can be analysed and replayed afterwards.
+//!
+//! File format: [`MAGIC`], then one record per nibble: a byte with the direction in the top
+//! bit and the nibble in the low four, followed by the time since the previous record in
+//! microseconds as LEB128. A record cut short by a crash is ignored when reading.
+
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{
+    clock::Clock,
+    encoder::Outgoing,
+    link::Link,
+    session::{Event, Session},
+};
+
+/// Start of every capture file, the last byte is the format version
+pub const MAGIC: &[u8; 6] = b"V7CAP\x01";
+
+const SENT: u8 = 0b1000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record {
+    /// Since the capture started
+    pub at: Duration,
+    pub direction: Direction,
+    pub nibble: u8,
+}
+
+/// Writes records as they happen, every record is written through at once,
+/// so the capture survives the program dying mid-transfer
+pub struct Recorder {
+    out: Box<dyn Write + Send>,
+    start: Instant,
+    last: Duration,
+}
+
+impl Recorder {
+    pub fn new(mut out: Box<dyn Write + Send>, start: Instant) -> io::Result<Self> {
+        out.write_all(MAGIC)?;
+        Ok(Self {
+            out,
+            start,
+            last: Duration::ZERO,
+        })
+    }
+
+    pub fn record(&mut self, now: Instant, direction: Direction, nibble: u8) -> io::Result<()> {
+        // sender and receiver thread race for the lock, time never goes backwards
+        let at = now.saturating_duration_since(self.start).max(self.last);
+        let mut delta = (at - self.last).as_micros() as u64;
+        self.last = at;
+
+        let mut bytes = vec![match direction {
+            Direction::Sent => SENT | nibble & 0xF,
+            Direction::Received => nibble & 0xF,
+        }];
+        loop {
+            let byte = (delta & 0x7F) as u8;
+            delta >>= 7;
+            if delta == 0 {
+                bytes.push(byte);
+                break;
+            }
+            bytes.push(byte | 0x80);
+        }
+        self.out.write_all(&bytes)
+    }
+}
+
+/// [`Link`] that records everything passing through it, clones share the recorder
+pub struct RecordingLink {
+    inner: Box<dyn Link>,
+    recorder: Arc<Mutex<Recorder>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl RecordingLink {
+    pub fn new(
+        inner: Box<dyn Link>,
+        out: Box<dyn Write + Send>,
+        clock: Arc<dyn Clock>,
+    ) -> io::Result<Self> {
+        let recorder = Recorder::new(out, clock.now())?;
+        Ok(Self {
+            inner,
+            recorder: Arc::new(Mutex::new(recorder)),
+            clock,
+        })
+    }
+
+    fn record(&self, direction: Direction, nibble: u8) -> io::Result<()> {
+        let now = self.clock.now();
+        self.recorder.lock().unwrap().record(now, direction, nibble)
+    }
+}
+
+impl Link for RecordingLink {
+    fn send(&mut self, nibble: u8) -> io::Result<()> {
+        self.inner.send(nibble)?;
+        self.record(Direction::Sent, nibble)
+    }
+
+    fn receive(&mut self) -> io::Result<u8> {
+        let nibble = self.inner.receive()?;
+        self.record(Direction::Received, nibble)?;
+        Ok(nibble)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Link>> {
+        Ok(Box::new(Self {
+            inner: self.inner.try_clone()?,
+            recorder: Arc::clone(&self.recorder),
+            clock: Arc::clone(&self.clock),
+        }))
+    }
+}
+
+pub fn is_capture(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Records of a capture file, `InvalidData` if it is none
+pub fn read_capture(bytes: &[u8]) -> io::Result<Vec<Record>> {
+    let Some(mut rest) = bytes.strip_prefix(MAGIC) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a v7 capture",
+        ));
+    };
+    let mut records = Vec::new();
+    let mut at = Duration::ZERO;
+    'records: while let Some((&head, tail)) = rest.split_first() {
+        let mut delta: u64 = 0;
+        let mut used = 0;
+        loop {
+            let Some(&byte) = tail.get(used) else {
+                break 'records;
+            };
+            delta |= u64::from(byte & 0x7F) << (7 * used);
+            used += 1;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            if used == 10 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "timestamp too long",
+                ));
+            }
+        }
+        rest = &tail[used..];
+        at += Duration::from_micros(delta);
+        records.push(Record {
+            at,
+            direction: if head & SENT != 0 {
+                Direction::Sent
+            } else {
+                Direction::Received
+            },
+            nibble: head & 0xF,
+        });
+    }
+    Ok(records)
+}
+
+/// Nibbles of one direction
+pub fn nibbles(records: &[Record], direction: Direction) -> Vec<u8> {
+    records
+        .iter()
+        .filter(|record| record.direction == direction)
+        .map(|record| record.nibble)
+        .collect()
+}
+
+pub struct Replay {
+    /// Events with the time of the nibble that caused them
+    pub events: Vec<(Duration, Event)>,
+    /// What the session would have sent in response
+    pub outgoing: Vec<(Duration, Outgoing)>,
+}
+
+/// Feeds the received nibbles of a capture into `session` at their recorded times
+/// (relative to `start`). Only the receiving side is replayed, the session's timers
+/// are not run, so the outcome depends on the capture alone.
+pub fn replay(records: &[Record], session: &mut Session, start: Instant) -> Replay {
+    let mut replay = Replay {
+        events: Vec::new(),
+        outgoing: Vec::new(),
+    };
+    for record in records
+        .iter()
+        .filter(|record| record.direction == Direction::Received)
+    {
+        let mut outgoing = Vec::new();
+        if let Some(event) = session.on_nibble(record.nibble, start + record.at, &mut outgoing) {
+            replay.events.push((record.at, event));
+        }
+        replay
+            .outgoing
+            .extend(outgoing.into_iter().map(|out| (record.at, out)));
+    }
+    replay
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lets the test read what a [`Recorder`] wrote
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(bytes)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn capture(records: &[Record]) -> Vec<u8> {
+        let out = Shared::default();
+        let start = Instant::now();
+        let mut recorder = Recorder::new(Box::new(out.clone()), start).unwrap();
+        for record in records {
+            recorder
+                .record(start + record.at, record.direction, record.nibble)
+                .unwrap();
+        }
+        let bytes = out.0.lock().unwrap().clone();
+        bytes
+    }
+
+    fn record(micros: u64, direction: Direction, nibble: u8) -> Record {
+        Record {
+            at: Duration::from_micros(micros),
+            direction,
+            nibble,
+        }
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let records = [
+            record(0, Direction::Sent, 0x3),
+            // same time, delta 0
+            record(0, Direction::Received, 0x3),
+            record(127, Direction::Sent, 0xF),
+            // two byte delta
+            record(128 + 127, Direction::Received, 0x0),
+            // three byte delta
+            record(128 + 127 + 20_000, Direction::Sent, 0x9),
+            record(3_600_000_000 + 255 + 20_000, Direction::Received, 0x6),
+        ];
+        let bytes = capture(&records);
+        assert!(is_capture(&bytes));
+        // one byte per nibble and 1, 1, 1, 2, 3 and 5 bytes of delta
+        assert_eq!(bytes.len(), MAGIC.len() + records.len() + 13);
+        assert_eq!(read_capture(&bytes).unwrap(), records);
+    }
+
+    #[test]
+    fn time_never_goes_backwards() {
+        let start = Instant::now();
+        let out = Shared::default();
+        let mut recorder = Recorder::new(Box::new(out.clone()), start).unwrap();
+        recorder
+            .record(start + Duration::from_millis(5), Direction::Sent, 1)
+            .unwrap();
+        recorder
+            .record(start + Duration::from_millis(2), Direction::Received, 2)
+            .unwrap();
+        let records = read_capture(&out.0.lock().unwrap()).unwrap();
+        assert_eq!(records[1].at, Duration::from_millis(5));
+    }
+
+    #[test]
+    fn cut_off_record_is_ignored() {
+        let records = [
+            record(10, Direction::Sent, 0x1),
+            record(10 + 300, Direction::Sent, 0x2),
+        ];
+        let bytes = capture(&records);
+        // the second record is 0x82, then its two byte delta
+        for cut in 1..=3 {
+            let read = read_capture(&bytes[..bytes.len() - cut]).unwrap();
+            assert_eq!(read, records[..1], "{cut} bytes cut off");
+        }
+    }
+
+    #[test]
+    fn overlong_timestamp_is_rejected() {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(SENT | 0x5);
+        bytes.extend([0xFF; 10]);
+        bytes.push(0x00);
+        let error = read_capture(&bytes).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn other_files_are_no_capture() {
+        assert!(!is_capture(b"$timescale"));
+        let error = read_capture(b"V7CAP\x02").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
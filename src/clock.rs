@@ -0,0 +1,112 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// `thread::sleep` overshoots by up to a scheduler tick, the last part before a deadline is spun
+const SPIN_MARGIN: Duration = Duration::from_micros(500);
+
+/// Source of monotonic time for the sender scheduler and the ARQ timers
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Blocks until `now()` has reached `deadline`
+    fn sleep_until(&self, deadline: Instant);
+}
+
+/// Real monotonic time
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    /// Sleeps most of the way to `deadline` and spins for the rest
+    fn sleep_until(&self, deadline: Instant) {
+        let now = Instant::now();
+        if deadline > now + SPIN_MARGIN {
+            thread::sleep(deadline - now - SPIN_MARGIN);
+        }
+        while Instant::now() < deadline {
+            std::hint::spin_loop();
+        }
+    }
+}
+
+/// Manually advanced time, sleeping jumps straight to the deadline.
+/// Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct VirtualClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl VirtualClock {
+    pub fn new() -> Self {
+        Self {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    fn sleep_until(&self, deadline: Instant) {
+        let mut now = self.now.lock().unwrap();
+        if deadline > *now {
+            *now = deadline;
+        }
+    }
+}
+
+/// One-shot deadline, checked against whatever clock the caller uses
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timer {
+    deadline: Option<Instant>,
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Self { deadline: None }
+    }
+
+    pub fn start(&mut self, now: Instant, timeout: Duration) {
+        self.deadline = Some(now + timeout);
+    }
+
+    pub fn stop(&mut self) {
+        self.deadline = None;
+    }
+
+    /// Moves a running deadline back by `by`
+    pub fn extend(&mut self, by: Duration) {
+        if let Some(deadline) = &mut self.deadline {
+            *deadline += by;
+        }
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.deadline.is_some()
+    }
+
+    pub fn expired(&self, now: Instant) -> bool {
+        self.deadline.is_some_and(|deadline| now >= deadline)
+    }
+}
@@ -1,8 +1,8 @@
-pub const MAX_SIZE: u16 = 123; // packet size in bytes
+pub const MAX_SIZE: u16 = 128; // codeword size in bytes (header, data and ECC), longer packets are split
 pub const BYTE_EXPANSION: usize = 4; // in bits
 
 // Every size given in bytes!
 pub const SOT_SIZE: usize = 1;
 pub const TRANSMISSION_HEADER_SIZE: usize = 7;
-pub const PACKET_HEADER_SIZE: usize = 6;
+pub const PACKET_HEADER_SIZE: usize = 11;
 pub const EOT_SIZE: usize = 1;
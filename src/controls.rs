@@ -1,9 +1,121 @@
-pub const SOT: u8 = 0b111; //  7 Start of transmission
-pub const SOTH: u8 = 0b0101; //  5 Start of transmission header
-pub const EOT: u8 = 0b100; //  4 End of transmission
-pub const SOH: u8 = 0b1; //  1 Start of header
-pub const SOTX: u8 = 0b10; //  2 Start of text
-                           // pub const EOTX: u8  = 0b11;   //  3 End of text
-                           //pub const ENQ:  u8  = 0b101;   //  5 Enquiry
-                           //pub const ACK:  u8  = 0b110;   //  6 Acknowledge
-                           //pub const NAC:  u8  = 0b10101; // 21 Not acknowledge
+//! Symbols on the wire: a byte plus a flag telling data from control codes.
+
+use std::fmt;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Control {
+    Sot = 0b111,   //  7 Start of transmission
+    Soth = 0b0101, //  5 Start of transmission header
+    Eot = 0b100,   //  4 End of transmission
+    Soh = 0b1,     //  1 Start of header
+    Sotx = 0b10,   //  2 Start of text
+    Eotx = 0b11,   //  3 End of text
+    Enq = 0b1001,  //  9 Enquiry (5 is SOTH)
+    Ack = 0b110,   //  6 Acknowledge
+    Nac = 0b10101, // 21 Not acknowledge
+}
+
+impl Control {
+    pub const ALL: [Control; 9] = [
+        Control::Sot,
+        Control::Soth,
+        Control::Eot,
+        Control::Soh,
+        Control::Sotx,
+        Control::Eotx,
+        Control::Enq,
+        Control::Ack,
+        Control::Nac,
+    ];
+
+    /// Byte sent for this code
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+}
+
+impl fmt::Display for Control {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Control::Sot => "SOT",
+            Control::Soth => "SOTH",
+            Control::Eot => "EOT",
+            Control::Soh => "SOH",
+            Control::Sotx => "SOTX",
+            Control::Eotx => "EOTX",
+            Control::Enq => "ENQ",
+            Control::Ack => "ACK",
+            Control::Nac => "NAC",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A byte flagged as control that is no known code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownControl(pub u8);
+
+impl fmt::Display for UnknownControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown control code {:#04x}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownControl {}
+
+impl TryFrom<u8> for Control {
+    type Error = UnknownControl;
+
+    fn try_from(code: u8) -> Result<Self, Self::Error> {
+        Control::ALL
+            .into_iter()
+            .find(|control| control.code() == code)
+            .ok_or(UnknownControl(code))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Symbol {
+    Data(u8),
+    Control(Control),
+}
+
+impl Symbol {
+    /// Symbol of a byte and its control flag as received
+    pub fn from_wire(byte: u8, is_control: bool) -> Result<Self, UnknownControl> {
+        if is_control {
+            Control::try_from(byte).map(Symbol::Control)
+        } else {
+            Ok(Symbol::Data(byte))
+        }
+    }
+
+    /// Byte and control flag put on the wire
+    pub fn to_wire(self) -> (u8, bool) {
+        match self {
+            Symbol::Data(byte) => (byte, false),
+            Symbol::Control(control) => (control.code(), true),
+        }
+    }
+
+    /// The byte, whether data or control code
+    pub fn byte(self) -> u8 {
+        self.to_wire().0
+    }
+
+    pub fn is_control(self) -> bool {
+        matches!(self, Symbol::Control(_))
+    }
+}
+
+impl From<Control> for Symbol {
+    fn from(control: Control) -> Self {
+        Symbol::Control(control)
+    }
+}
+
+/// Symbols of data bytes
+pub fn data_symbols(bytes: &[u8]) -> impl Iterator<Item = Symbol> + '_ {
+    bytes.iter().map(|&byte| Symbol::Data(byte))
+}
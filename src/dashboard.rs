@@ -0,0 +1,277 @@
+//! State of every packet of the peer's data while it arrives, with throughput, corrected errors
+//! and retransmissions, for a live view of how much of the file is there.
+//!
+//! The [`crate::session::Session`] keeps it up to date, `Display` draws it as a grid of packet
+//! IDs colored by state, or a block per packet once there are too many IDs for the screen.
+
+use std::fmt;
+use std::time::Instant;
+
+use ansi_term::Color::{Blue, Cyan, Green, Red, Yellow};
+
+/// Packets shown by their ID, more are shown as blocks
+const MAX_IDS: usize = 256;
+const IDS_PER_ROW: usize = 16;
+const BLOCKS_PER_ROW: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketState {
+    Pending,
+    Clean,
+    /// Corrected errors
+    Repaired(usize),
+    /// Rebuilt from the parity packets of the outer code
+    Rebuilt,
+    Unrecoverable,
+    /// Asked the peer for it again, by enquiry or NAK
+    Requested,
+}
+
+impl PacketState {
+    pub fn is_received(self) -> bool {
+        matches!(
+            self,
+            PacketState::Clean | PacketState::Repaired(_) | PacketState::Rebuilt
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PacketMap {
+    /// Index is the packet ID - 1, there is no packet 0
+    states: Vec<PacketState>,
+    started: Option<Instant>,
+    last: Option<Instant>,
+    /// Data bytes of the packets received, each packet counted once
+    payload: usize,
+    /// Bytes of the packets as they arrived, with ECC, for the error rate
+    received: usize,
+    corrected: usize,
+    /// Nibbles that arrived
+    nibbles: usize,
+    /// IDs of every copy of a packet that arrived beyond repair
+    unrecoverable: Vec<u16>,
+    /// Enquiries and NAKs we sent
+    requests: usize,
+    /// The peer did not answer in time
+    timeouts: usize,
+    /// Packets we resent because the peer asked
+    resends: usize,
+}
+
+impl PacketMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A nibble arrived at `now`, the first one starts the clock for the throughput
+    pub fn on_nibble(&mut self, now: Instant) {
+        self.nibbles += 1;
+        self.started.get_or_insert(now);
+        self.last = Some(now);
+    }
+
+    /// The peer's data has `total` packets
+    pub fn set_total(&mut self, total: u16) {
+        self.states.resize(total as usize, PacketState::Pending);
+    }
+
+    /// Packet `id` arrived, `bytes` with ECC, `data` of it payload.
+    /// A packet received before stays received, a bad copy does not undo it.
+    pub fn on_packet(&mut self, id: u16, state: PacketState, bytes: usize, data: usize) {
+        self.received += bytes;
+        match state {
+            PacketState::Repaired(errors) => self.corrected += errors,
+            PacketState::Unrecoverable => self.unrecoverable.push(id),
+            _ => {}
+        }
+        let Some(slot) = self.slot(id) else {
+            // parity packets have IDs behind the data
+            return;
+        };
+        if slot.is_received() {
+            return;
+        }
+        *slot = state;
+        if state.is_received() {
+            self.payload += data;
+        }
+    }
+
+    /// Packet `id` was rebuilt from parity, `data` bytes of payload
+    pub fn on_rebuilt(&mut self, id: u16, data: usize) {
+        if let Some(slot) = self.slot(id).filter(|slot| !slot.is_received()) {
+            *slot = PacketState::Rebuilt;
+            self.payload += data;
+        }
+    }
+
+    /// An enquiry or NAK for `ids` was sent
+    pub fn on_requested(&mut self, ids: &[u16]) {
+        self.requests += 1;
+        for &id in ids {
+            if let Some(slot) = self.slot(id).filter(|slot| !slot.is_received()) {
+                *slot = PacketState::Requested;
+            }
+        }
+    }
+
+    /// The peer did not answer an enquiry or our data in time
+    pub fn on_timeout(&mut self) {
+        self.timeouts += 1;
+    }
+
+    /// `count` packets were resent for the peer
+    pub fn on_resend(&mut self, count: usize) {
+        self.resends += count;
+    }
+
+    fn slot(&mut self, id: u16) -> Option<&mut PacketState> {
+        self.states.get_mut((id as usize).checked_sub(1)?)
+    }
+
+    pub fn states(&self) -> &[PacketState] {
+        &self.states
+    }
+
+    pub fn count(&self, matches: impl Fn(PacketState) -> bool) -> usize {
+        self.states.iter().filter(|&&state| matches(state)).count()
+    }
+
+    /// Payload bytes per second since the first nibble arrived
+    pub fn throughput(&self) -> f64 {
+        match (self.started, self.last) {
+            (Some(started), Some(last)) if last > started => {
+                self.payload as f64 / (last - started).as_secs_f64()
+            }
+            _ => 0.0,
+        }
+    }
+
+    /// Share of the received bytes the ECC had to correct
+    pub fn error_rate(&self) -> f64 {
+        match self.received {
+            0 => 0.0,
+            received => self.corrected as f64 / received as f64,
+        }
+    }
+
+    /// Errors corrected in every packet, index is the ID - 1
+    pub fn corrected_errors(&self) -> Vec<usize> {
+        self.states
+            .iter()
+            .map(|state| match state {
+                PacketState::Repaired(errors) => *errors,
+                _ => 0,
+            })
+            .collect()
+    }
+
+    /// All errors corrected, parity packets and copies that were not needed included
+    pub fn corrected(&self) -> usize {
+        self.corrected
+    }
+
+    pub fn payload(&self) -> usize {
+        self.payload
+    }
+
+    pub fn nibbles(&self) -> usize {
+        self.nibbles
+    }
+
+    pub fn unrecoverable(&self) -> &[u16] {
+        &self.unrecoverable
+    }
+
+    pub fn timeouts(&self) -> usize {
+        self.timeouts
+    }
+
+    pub fn requests(&self) -> usize {
+        self.requests
+    }
+
+    pub fn resends(&self) -> usize {
+        self.resends
+    }
+}
+
+fn paint(state: PacketState, text: String) -> String {
+    match state {
+        PacketState::Pending => text,
+        PacketState::Clean => Green.paint(text).to_string(),
+        PacketState::Repaired(_) => Yellow.paint(text).to_string(),
+        PacketState::Rebuilt => Cyan.paint(text).to_string(),
+        PacketState::Unrecoverable => Red.paint(text).to_string(),
+        PacketState::Requested => Blue.paint(text).to_string(),
+    }
+}
+
+impl fmt::Display for PacketMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Packets {}/{}: {}, {}, {}, {}, {}",
+            self.count(PacketState::is_received),
+            self.states.len(),
+            paint(
+                PacketState::Clean,
+                format!("{} clean", self.count(|s| s == PacketState::Clean))
+            ),
+            paint(
+                PacketState::Repaired(0),
+                format!(
+                    "{} repaired ({} errors)",
+                    self.count(|s| matches!(s, PacketState::Repaired(_))),
+                    self.corrected
+                )
+            ),
+            paint(
+                PacketState::Rebuilt,
+                format!("{} rebuilt", self.count(|s| s == PacketState::Rebuilt))
+            ),
+            paint(
+                PacketState::Unrecoverable,
+                format!(
+                    "{} unrecoverable",
+                    self.count(|s| s == PacketState::Unrecoverable)
+                )
+            ),
+            paint(
+                PacketState::Requested,
+                format!("{} requested", self.count(|s| s == PacketState::Requested))
+            ),
+        )?;
+
+        if self.states.len() <= MAX_IDS {
+            for (row, states) in self.states.chunks(IDS_PER_ROW).enumerate() {
+                writeln!(f)?;
+                for (i, &state) in states.iter().enumerate() {
+                    let id = row * IDS_PER_ROW + i + 1;
+                    write!(f, "{} ", paint(state, format!("{id:>4}")))?;
+                }
+            }
+        } else {
+            for states in self.states.chunks(BLOCKS_PER_ROW) {
+                writeln!(f)?;
+                for &state in states {
+                    let block = match state {
+                        PacketState::Pending => "·",
+                        _ => "■",
+                    };
+                    write!(f, "{}", paint(state, block.to_string()))?;
+                }
+            }
+        }
+
+        write!(
+            f,
+            "\n{:.1} B/s payload, {:.2}% of bytes corrected, {} requests, {} packets resent",
+            self.throughput(),
+            self.error_rate() * 100.0,
+            self.requests,
+            self.resends
+        )
+    }
+}
@@ -0,0 +1,473 @@
+//! Offline dissector for captured nibble streams, one nibble per byte as a link receives them.
+//!
+//! Breaks a capture down into idle clock (preamble), frames with their header and packets,
+//! standalone NAKs and whatever could not be parsed, which is shown as annotated hex.
+
+use std::fmt;
+use std::ops::Range;
+
+use ansi_term::Color::{Blue, Cyan, Green, Red, Yellow};
+
+use crate::{
+    controls::{Control, Symbol, UnknownControl},
+    encoder::unclock_symbol,
+    enquiry::decode_ids,
+    protocol::{Message, Nak, ProtocolDecoder, TransmissionHeader},
+    utilities::squash_nibbles,
+};
+
+/// Hex shown of an unparsed stretch before it is cut short
+const MAX_HEX: usize = 64;
+
+pub enum Item {
+    /// Clock toggling with the data lines low: preamble or the pause between frames
+    Idle {
+        at: usize,
+        nibbles: usize,
+    },
+    Frame {
+        at: usize,
+        header: TransmissionHeader,
+        /// Header bytes the ECC corrected
+        corrected: usize,
+        packets: Vec<PacketInfo>,
+        /// NAKs sent within the frame, `None` if one was beyond repair
+        naks: Vec<Option<Vec<u16>>>,
+        eot: bool,
+    },
+    /// Started with SOT, but the header could not be decoded
+    BrokenFrame {
+        at: usize,
+        symbols: Vec<Result<Symbol, UnknownControl>>,
+    },
+    /// `None` if beyond repair
+    Nak {
+        at: usize,
+        ids: Option<Vec<u16>>,
+    },
+    Unparsed {
+        at: usize,
+        nibbles: Vec<u8>,
+    },
+}
+
+pub struct PacketInfo {
+    pub id: u16,
+    /// Declared data size in bytes
+    pub size: u16,
+    /// Corrected errors, `None` if beyond repair
+    pub errors: Option<usize>,
+    /// IDs an enquiry packet asks for
+    pub ids: Option<Vec<u16>>,
+}
+
+/// Positions of the nibbles where the clock changed
+pub fn edge_positions(capture: &[u8]) -> Vec<usize> {
+    let mut positions: Vec<usize> = Vec::new();
+    for (i, &nibble) in capture.iter().enumerate() {
+        if positions
+            .last()
+            .is_none_or(|&last| (capture[last] ^ nibble) & 0b1000 != 0)
+        {
+            positions.push(i);
+        }
+    }
+    positions
+}
+
+/// Keeps only the nibbles where the clock changed, like the receiver does
+pub fn clock_edges(capture: &[u8]) -> Vec<u8> {
+    edge_positions(capture)
+        .into_iter()
+        .map(|i| capture[i] & 0xF)
+        .collect()
+}
+
+/// Frames (SOT up to EOT) and standalone NAKs in clocked nibbles
+pub fn frames(nibbles: &[u8]) -> Vec<Range<usize>> {
+    let mut frames = Vec::new();
+    let mut at = 0;
+    while at + 3 <= nibbles.len() {
+        let end = match unclock_symbol(&nibbles[at..at + 3]) {
+            Ok(Symbol::Control(Control::Sot)) => frame_end(nibbles, at),
+            Ok(Symbol::Control(Control::Nac)) => nak_end(nibbles, at),
+            _ => {
+                at += 1;
+                continue;
+            }
+        };
+        frames.push(at..end);
+        at = end;
+    }
+    frames
+}
+
+/// Dissects a raw capture, offsets of the items count clocked nibbles
+pub fn dissect(capture: &[u8]) -> Vec<Item> {
+    let nibbles = clock_edges(capture);
+    let mut items = Vec::new();
+    let mut unparsed_from = 0;
+    for frame in frames(&nibbles) {
+        items.extend(unparsed(&nibbles, unparsed_from, frame.start));
+        items.push(
+            match unclock_symbol(&nibbles[frame.start..frame.start + 3]) {
+                Ok(Symbol::Control(Control::Sot)) => self::frame(&nibbles, frame.start, frame.end),
+                _ => nak(&nibbles, frame.start, frame.end),
+            },
+        );
+        unparsed_from = frame.end;
+    }
+    items.extend(unparsed(&nibbles, unparsed_from, nibbles.len()));
+    items
+}
+
+fn symbols(nibbles: &[u8]) -> impl Iterator<Item = Result<Symbol, UnknownControl>> + '_ {
+    nibbles.chunks_exact(3).map(unclock_symbol)
+}
+
+/// A frame runs up to its EOT or the end of the capture
+fn frame_end(nibbles: &[u8], at: usize) -> usize {
+    let len = symbols(&nibbles[at..])
+        .position(|symbol| symbol == Ok(Symbol::Control(Control::Eot)))
+        .map_or(nibbles.len() - at, |eot| (eot + 1) * 3);
+    at + len
+}
+
+fn nak_end(nibbles: &[u8], at: usize) -> usize {
+    let count = nibbles
+        .get(at + 3..at + 6)
+        .map_or(0, |count| unclock_symbol(count).map_or(0, Symbol::byte));
+    (at + Nak::symbol_count(count as usize) * 3).min(nibbles.len())
+}
+
+fn bytes(nibbles: &[u8]) -> Vec<u8> {
+    symbols(nibbles)
+        .map(|symbol| symbol.map_or_else(|UnknownControl(byte)| byte, Symbol::byte))
+        .collect()
+}
+
+fn frame(nibbles: &[u8], at: usize, end: usize) -> Item {
+    let frame = &nibbles[at..end];
+    let Some(transmission) = ProtocolDecoder::new(squash_nibbles(frame)).decode() else {
+        return Item::BrokenFrame {
+            at,
+            symbols: symbols(frame).collect(),
+        };
+    };
+    let header = transmission.header;
+    let frame_bytes = bytes(frame);
+
+    // header bytes as received against the repaired ones
+    let sent: Vec<u8> = header
+        .to_binary()
+        .iter()
+        .map(|symbol| symbol.byte())
+        .collect();
+    let corrected = frame_bytes
+        .iter()
+        .zip(&sent)
+        .filter(|(received, sent)| received != sent)
+        .count();
+
+    let fec = header.fec.codec();
+    let packets = transmission
+        .packets
+        .into_iter()
+        .map(|packet| {
+            let size = packet.header.len;
+            match packet.repair(&*fec) {
+                Some((packet, errors)) => PacketInfo {
+                    id: packet.header.id,
+                    size,
+                    errors: Some(errors),
+                    ids: (header.message == Message::Enquiry)
+                        .then(|| decode_ids(&packet.data))
+                        .flatten(),
+                },
+                None => PacketInfo {
+                    id: packet.header.id,
+                    size,
+                    errors: None,
+                    ids: None,
+                },
+            }
+        })
+        .collect();
+
+    let naks = symbols(frame)
+        .enumerate()
+        .skip(1)
+        .filter(|(_, symbol)| *symbol == Ok(Symbol::Control(Control::Nac)))
+        .map(|(i, _)| Nak::from_bytes(&frame_bytes[i + 1..]).map(|nak| nak.ids))
+        .collect();
+    let eot = symbols(frame).last() == Some(Ok(Symbol::Control(Control::Eot)));
+
+    Item::Frame {
+        at,
+        header,
+        corrected,
+        packets,
+        naks,
+        eot,
+    }
+}
+
+fn nak(nibbles: &[u8], at: usize, end: usize) -> Item {
+    Item::Nak {
+        at,
+        ids: Nak::from_bytes(&bytes(&nibbles[at + 3..end])).map(|nak| nak.ids),
+    }
+}
+
+/// Less than a symbol, e.g. the padding after a frame, is not worth mentioning
+fn unparsed(nibbles: &[u8], from: usize, to: usize) -> Option<Item> {
+    let stretch = &nibbles[from..to];
+    if stretch.len() < 3 {
+        None
+    } else if stretch.iter().all(|nibble| nibble & 0b0111 == 0) {
+        Some(Item::Idle {
+            at: from,
+            nibbles: stretch.len(),
+        })
+    } else {
+        Some(Item::Unparsed {
+            at: from,
+            nibbles: stretch.to_vec(),
+        })
+    }
+}
+
+impl fmt::Display for Item {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Item::Idle { at, nibbles } => {
+                write!(f, "{at:>8}  {}  {nibbles} nibbles", Blue.paint("idle"))
+            }
+            Item::Frame {
+                at,
+                header,
+                corrected,
+                packets,
+                naks,
+                eot,
+            } => {
+                write!(
+                    f,
+                    "{at:>8}  {}  {:?} v{}, {} packets, {:?}",
+                    Yellow.paint("SOT"),
+                    header.message,
+                    header.version,
+                    header.total_packets,
+                    header.fec
+                )?;
+                if let Some(outer_code) = header.outer_code {
+                    write!(f, ", outer {}+{}", outer_code.data, outer_code.parity)?;
+                }
+                if let Some(interleaver) = header.interleaver {
+                    write!(
+                        f,
+                        ", interleaved {}x{}",
+                        interleaver.depth, interleaver.row_len
+                    )?;
+                }
+                match corrected {
+                    0 => write!(f, ", header {}", Green.paint("ok"))?,
+                    n => write!(
+                        f,
+                        ", header {}",
+                        Yellow.paint(format!("{n} bytes corrected"))
+                    )?,
+                }
+                for packet in packets {
+                    write!(f, "\n{:>10}{}  {:>5} bytes  ", "", packet.id, packet.size)?;
+                    match packet.errors {
+                        Some(0) => write!(f, "{}", Green.paint("ok"))?,
+                        Some(n) => write!(f, "{}", Yellow.paint(format!("{n} errors corrected")))?,
+                        None => write!(f, "{}", Red.paint("unrecoverable"))?,
+                    }
+                    if let Some(ids) = &packet.ids {
+                        write!(f, "  requests {ids:?}")?;
+                    }
+                }
+                for nak in naks {
+                    write!(f, "\n{:>10}{}  ", "", Cyan.paint("NAK"))?;
+                    match nak {
+                        Some(ids) => write!(f, "{ids:?}")?,
+                        None => write!(f, "{}", Red.paint("damaged"))?,
+                    }
+                }
+                match eot {
+                    true => write!(f, "\n{:>10}{}", "", Yellow.paint("EOT")),
+                    false => write!(f, "\n{:>10}{}", "", Red.paint("no EOT")),
+                }
+            }
+            Item::BrokenFrame { at, symbols } => {
+                write!(f, "{at:>8}  {}", Red.paint("unparsable frame"))?;
+                for line in symbols.chunks(16) {
+                    write!(f, "\n{:>10}", "")?;
+                    for symbol in line {
+                        match symbol {
+                            Ok(Symbol::Data(byte)) => write!(f, "{byte:02x} ")?,
+                            Ok(Symbol::Control(control)) => {
+                                write!(f, "{} ", Yellow.paint(format!("[{control}]")))?;
+                            }
+                            Err(UnknownControl(byte)) => {
+                                write!(f, "{} ", Red.paint(format!("?{byte:02x}")))?;
+                            }
+                        }
+                    }
+                }
+                Ok(())
+            }
+            Item::Nak { at, ids } => {
+                write!(f, "{at:>8}  {}  ", Cyan.paint("NAK"))?;
+                match ids {
+                    Some(ids) => write!(f, "{ids:?}"),
+                    None => write!(f, "{}", Red.paint("damaged")),
+                }
+            }
+            Item::Unparsed { at, nibbles } => {
+                write!(
+                    f,
+                    "{at:>8}  {}  {} nibbles ",
+                    Red.paint("unparsed"),
+                    nibbles.len()
+                )?;
+                for nibble in nibbles.iter().take(MAX_HEX) {
+                    write!(f, "{nibble:x}")?;
+                }
+                if nibbles.len() > MAX_HEX {
+                    write!(f, "…")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        encoder::{clock_symbol, preamble, wire_nibbles},
+        fec::FecScheme,
+        protocol::Transmission,
+    };
+
+    /// Data transmission of packets with 5, 7 and 9 bytes
+    fn transmission() -> Transmission {
+        let chunks = [5, 7, 9].map(|len| vec![len as u8; len]).to_vec();
+        Transmission::from_chunks(chunks, FecScheme::default(), None).unwrap()
+    }
+
+    /// Wire nibbles of 4 preamble pairs and the segments, as the send queue puts them
+    fn wire(segments: &[Vec<Symbol>]) -> Vec<u8> {
+        let mut wire: Vec<u8> = preamble(4).collect();
+        let mut clock = 1;
+        for &symbol in segments.iter().flatten() {
+            wire.extend(
+                clock_symbol(symbol, clock)
+                    .into_iter()
+                    .flat_map(wire_nibbles),
+            );
+            clock ^= 1;
+        }
+        wire
+    }
+
+    /// Flips all bits of the data symbol at `at` of `segment`
+    fn damage(segment: &mut [Symbol], at: usize) {
+        let Symbol::Data(byte) = segment[at] else {
+            panic!("no data symbol at {at}");
+        };
+        segment[at] = Symbol::Data(!byte);
+    }
+
+    #[test]
+    fn frame_with_a_nak_is_dissected() {
+        let mut segments: Vec<_> = transmission().into_segments().collect();
+        segments.insert(2, Nak::new(vec![4, 6]).to_binary());
+        let items = dissect(&wire(&segments));
+
+        assert_eq!(items.len(), 2);
+        assert!(matches!(items[0], Item::Idle { at: 0, nibbles: 8 }));
+        let Item::Frame {
+            at,
+            header,
+            corrected,
+            packets,
+            naks,
+            eot,
+        } = &items[1]
+        else {
+            panic!("no frame");
+        };
+        assert_eq!(*at, 8);
+        assert_eq!(header.message, Message::Data);
+        assert_eq!(header.total_packets, 3);
+        assert_eq!(*corrected, 0);
+        let packets: Vec<_> = packets
+            .iter()
+            .map(|packet| (packet.id, packet.size, packet.errors))
+            .collect();
+        assert_eq!(packets, [(1, 5, Some(0)), (2, 7, Some(0)), (3, 9, Some(0))]);
+        assert_eq!(naks, &[Some(vec![4, 6])]);
+        assert!(eot);
+    }
+
+    #[test]
+    fn corrected_errors_are_counted() {
+        let mut segments: Vec<_> = transmission().into_segments().collect();
+        damage(&mut segments[0], 2);
+        let packet = segments[2].len() - 3;
+        damage(&mut segments[2], packet);
+        damage(&mut segments[2], packet - 1);
+        let items = dissect(&wire(&segments));
+
+        let Item::Frame {
+            corrected, packets, ..
+        } = &items[1]
+        else {
+            panic!("no frame");
+        };
+        assert_eq!(*corrected, 1);
+        let errors: Vec<_> = packets.iter().map(|packet| packet.errors).collect();
+        assert_eq!(errors, [Some(0), Some(2), Some(0)]);
+    }
+
+    #[test]
+    fn broken_header_is_shown_symbol_by_symbol() {
+        let mut segments: Vec<_> = transmission().into_segments().collect();
+        for at in 1..segments[0].len() {
+            damage(&mut segments[0], at);
+        }
+        let symbol_count = segments.iter().map(Vec::len).sum();
+        let items = dissect(&wire(&segments));
+
+        assert_eq!(items.len(), 2);
+        let Item::BrokenFrame { at, symbols } = &items[1] else {
+            panic!("no broken frame");
+        };
+        assert_eq!(*at, 8);
+        assert_eq!(symbols.len(), symbol_count);
+        assert_eq!(symbols[0], Ok(Symbol::Control(Control::Sot)));
+        assert_eq!(symbols.last(), Some(&Ok(Symbol::Control(Control::Eot))));
+    }
+
+    #[test]
+    fn nak_between_frames_and_junk() {
+        let nak = Nak::new(vec![1]).to_binary();
+        let mut capture = wire(std::slice::from_ref(&nak));
+        // edges carrying data that is no symbol
+        capture.extend([0b1111, 0b0110, 0b1101, 0b0011, 0b1000, 0b0100]);
+        // a NAK cut short by the end of the capture
+        let cut = nak.len() / 2;
+        capture.extend(wire(&[nak[..cut].to_vec()]).split_off(8));
+        let items = dissect(&capture);
+
+        assert_eq!(items.len(), 4);
+        assert!(matches!(&items[1], Item::Nak { at: 8, ids: Some(ids) } if ids == &[1]));
+        assert!(matches!(&items[2], Item::Unparsed { nibbles, .. } if nibbles.len() == 6));
+        assert!(matches!(items[3], Item::Nak { ids: None, .. }));
+    }
+}
@@ -0,0 +1,447 @@
+use std::collections::VecDeque;
+use std::iter::Peekable;
+
+use crate::{
+    controls::{Symbol, UnknownControl},
+    nibble,
+    protocol::{Message, Transmission},
+};
+
+/// Number of `[clock, data]` nibble pairs sent before the first transmission
+pub const PREAMBLE_PAIRS: usize = 100;
+
+/// Wire nibbles of the preamble: the clock toggles while all data lines stay low
+pub fn preamble(pairs: usize) -> impl Iterator<Item = u8> {
+    std::iter::repeat_n([0b1000, 0], pairs).flatten()
+}
+
+/// Encodes one symbol into 3 clocked nibbles, `clock` is the clock of the first one
+/// C = clock, I = is_control, D = data
+/// CDDD
+/// CDDD
+/// CDDI
+pub fn clock_symbol(symbol: Symbol, clock: u8) -> [u8; 3] {
+    let (byte, is_control) = symbol.to_wire();
+    let one: u8 = (clock << 3) | (nibble!(byte >> 4).1 >> 1);
+    let two: u8 = ((clock ^ 1) << 3) | (nibble!(byte >> 2).1 & 0b0111);
+    let three: u8 = (clock << 3) | (nibble!(byte << 1).1 & 0b110) | u8::from(is_control);
+    [one, two, three]
+}
+
+/// Inverse of [`clock_symbol`], fails for a control flag on an unknown code
+pub fn unclock_symbol(nibbles: &[u8]) -> Result<Symbol, UnknownControl> {
+    let byte = (nibbles[0] & 0b111) << 5 | (nibbles[1] & 0b111) << 2 | (nibbles[2] & 0b110) >> 1;
+    Symbol::from_wire(byte, nibbles[2] & 0b1 == 1)
+}
+
+/// Expands a clocked nibble into the two nibbles put on the wire:
+/// first the data with the previous clock, then the clock edge
+pub fn wire_nibbles(clocked: u8) -> [u8; 2] {
+    let clock = clocked & 0b1000;
+    [(clocked & 0b0111) | (!clock & 0b1000), clocked]
+}
+
+/// Number of wire nibbles a frame of `symbols` symbols occupies
+pub fn wire_len(symbols: usize) -> usize {
+    symbols * 3 * 2
+}
+
+type Segments = Box<dyn Iterator<Item = Vec<Symbol>> + Send>;
+
+/// A lazily encoded transmission, produced segment by segment
+/// (transmission header, one segment per packet, EOT)
+pub struct Frame {
+    segments: Peekable<Segments>,
+    remaining: usize,
+    /// data transmission: resent packets may join it before its EOT
+    accepts_packets: bool,
+}
+
+/// Something a session wants to put on the wire
+pub enum Outgoing {
+    /// Sent after everything already queued
+    Frame(Frame),
+    /// Control segment (NAK), sent at the next segment boundary, even within a frame
+    Control(Vec<Symbol>),
+    /// Packets to resend: they join the data frame currently on the wire,
+    /// or go out as a transmission of their own if there is none
+    Resend(Transmission),
+}
+
+impl Frame {
+    pub fn new(
+        segments: impl Iterator<Item = Vec<Symbol>> + Send + 'static,
+        symbols: usize,
+    ) -> Self {
+        let segments: Segments = Box::new(segments);
+        Self {
+            segments: segments.peekable(),
+            remaining: wire_len(symbols),
+            accepts_packets: false,
+        }
+    }
+
+    pub fn from_transmission(transmission: Transmission) -> Self {
+        let symbols = transmission.symbol_count();
+        let accepts_packets = transmission.header.message == Message::Data;
+        Self {
+            accepts_packets,
+            ..Self::new(transmission.into_segments(), symbols)
+        }
+    }
+
+    /// Wire nibbles of this frame that have not been moved to the ring buffer yet
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+}
+
+/// Outgoing nibble queue: frames are encoded lazily into a small ring buffer,
+/// so popping a nibble or adding a frame never shifts the whole wire image
+pub struct SendQueue {
+    frames: VecDeque<Frame>,
+    segment: VecDeque<Symbol>,
+    ring: VecDeque<u8>,
+    /// clocked nibbles of the current frame already encoded
+    frame_nibbles: usize,
+    /// clock of the next clocked nibble, it keeps alternating across frames
+    clock: u8,
+    /// segments waiting for the next segment boundary, ahead of the frames
+    controls: VecDeque<Vec<Symbol>>,
+    resent: VecDeque<Vec<Symbol>>,
+    /// wire nibbles of `controls` and `resent`
+    injected: usize,
+    /// `segment` came from `controls` or `resent`
+    segment_injected: bool,
+}
+
+impl SendQueue {
+    pub fn new() -> Self {
+        Self {
+            frames: VecDeque::new(),
+            segment: VecDeque::new(),
+            ring: VecDeque::with_capacity(6),
+            frame_nibbles: 0,
+            // the line idles low, so the first edge raises the clock
+            clock: 1,
+            controls: VecDeque::new(),
+            resent: VecDeque::new(),
+            injected: 0,
+            segment_injected: false,
+        }
+    }
+
+    /// Queue starting with the clocked preamble
+    pub fn with_preamble(pairs: usize) -> Self {
+        let mut queue = Self::new();
+        queue.ring.extend(preamble(pairs));
+        queue
+    }
+
+    /// Appends a frame after everything already queued
+    pub fn push(&mut self, frame: Frame) {
+        self.frames.push_back(frame);
+    }
+
+    /// Splices a frame in right after the frame currently on the wire
+    pub fn splice(&mut self, frame: Frame) {
+        let at = usize::from(self.frame_nibbles > 0 || !self.segment.is_empty());
+        self.frames.insert(at.min(self.frames.len()), frame);
+    }
+
+    /// Queues what the session wants to send
+    pub fn submit(&mut self, outgoing: Outgoing) {
+        match outgoing {
+            Outgoing::Frame(frame) => self.push(frame),
+            Outgoing::Control(segment) => {
+                self.injected += segment.len() * 6;
+                self.controls.push_back(segment);
+            }
+            Outgoing::Resend(transmission) => {
+                if transmission.packets.is_empty() || !self.accepts_packets() {
+                    self.push(Frame::from_transmission(transmission));
+                    return;
+                }
+                // interleaved packets are resent as blocks of their own
+                for segment in Transmission::packet_segments(
+                    transmission.header.interleaver,
+                    transmission.packets,
+                ) {
+                    self.injected += segment.len() * 6;
+                    self.resent.push_back(segment);
+                }
+            }
+        }
+    }
+
+    /// A data frame is on the wire and has not started its EOT yet
+    fn accepts_packets(&mut self) -> bool {
+        self.frame_nibbles > 0
+            && self
+                .frames
+                .front_mut()
+                .is_some_and(|frame| frame.accepts_packets && frame.segments.peek().is_some())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Wire nibbles left to send
+    pub fn len(&self) -> usize {
+        self.ring.len() + self.frames.iter().map(Frame::remaining).sum::<usize>() + self.injected
+    }
+
+    /// Drops everything that has not been sent yet
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.segment.clear();
+        self.ring.clear();
+        self.frame_nibbles = 0;
+        self.controls.clear();
+        self.resent.clear();
+        self.injected = 0;
+    }
+
+    /// Next nibble to put on the wire
+    pub fn pop(&mut self) -> Option<u8> {
+        if self.ring.is_empty() {
+            self.refill();
+        }
+        self.ring.pop_front()
+    }
+
+    fn refill(&mut self) {
+        while self.ring.is_empty() {
+            if self.segment.is_empty() {
+                if !self.next_segment() {
+                    return;
+                }
+                continue;
+            }
+            let symbol = self.segment.pop_front().unwrap();
+            for clocked in clock_symbol(symbol, self.clock) {
+                self.ring.extend(wire_nibbles(clocked));
+            }
+            self.clock ^= 1;
+            self.frame_nibbles += 3;
+            if self.segment_injected {
+                self.injected = self.injected.saturating_sub(6);
+            } else if let Some(frame) = self.frames.front_mut() {
+                frame.remaining = frame.remaining.saturating_sub(6);
+            }
+        }
+    }
+
+    /// Loads the next segment or finishes the current frame, `false` if nothing is left
+    fn next_segment(&mut self) -> bool {
+        if self.frame_nibbles == 0 && !self.controls.is_empty() {
+            // between frames the pending controls go out as a frame of their own
+            let controls: Vec<_> = self.controls.drain(..).collect();
+            let symbols = controls.iter().map(Vec::len).sum::<usize>();
+            self.injected = self.injected.saturating_sub(symbols * 6);
+            self.frames
+                .push_front(Frame::new(controls.into_iter(), symbols));
+        }
+        let in_frame = self.frame_nibbles > 0;
+        let Some(frame) = self.frames.front_mut() else {
+            return false;
+        };
+        // never in front of the transmission header or behind EOT
+        if in_frame && frame.segments.peek().is_some() {
+            let injected = match self.controls.pop_front() {
+                Some(control) => Some(control),
+                None if frame.accepts_packets => self.resent.pop_front(),
+                None => None,
+            };
+            if let Some(segment) = injected {
+                self.segment.extend(segment);
+                self.segment_injected = true;
+                return true;
+            }
+        }
+        if let Some(segment) = frame.segments.next() {
+            self.segment.extend(segment);
+            self.segment_injected = false;
+            return true;
+        }
+        self.frames.pop_front();
+        self.frame_nibbles = 0;
+        true
+    }
+}
+
+impl Default for SendQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Iterator for SendQueue {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        self.pop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        dissect::{dissect, Item},
+        fec::FecScheme,
+        protocol::Nak,
+    };
+
+    /// Data transmission of `count` packets with 5 bytes each
+    fn data(count: u8) -> Transmission {
+        let chunks = (0..count).map(|packet| vec![packet; 5]).collect();
+        Transmission::from_chunks(chunks, FecScheme::default(), None).unwrap()
+    }
+
+    /// Pops one nibble, `len` shrinks by exactly one
+    fn pop(queue: &mut SendQueue) -> u8 {
+        let len = queue.len();
+        let nibble = queue.pop().unwrap();
+        assert_eq!(queue.len(), len - 1);
+        nibble
+    }
+
+    /// Pops everything, `len` always counts the nibbles still to come
+    fn drain(queue: &mut SendQueue) -> Vec<u8> {
+        let len = queue.len();
+        let wire: Vec<u8> = (0..len).map(|_| pop(queue)).collect();
+        assert_eq!(queue.pop(), None);
+        wire
+    }
+
+    /// Every nibble of the preamble is a clock edge, after it the clock changes
+    /// on the second nibble of every pair and only there
+    fn assert_clock_alternates(wire: &[u8], preamble_pairs: usize) {
+        let (preamble, frames) = wire.split_at(preamble_pairs * 2);
+        let mut clock = 0;
+        for nibble in preamble {
+            assert_ne!(nibble & 0b1000, clock);
+            clock = nibble & 0b1000;
+        }
+        assert!(frames.len().is_multiple_of(2));
+        for (i, pair) in frames.chunks(2).enumerate() {
+            assert_eq!(
+                pair[0] & 0b1000,
+                clock,
+                "clock edge with the data of pair {i}"
+            );
+            assert_ne!(pair[1] & 0b1000, clock, "no clock edge in pair {i}");
+            clock = pair[1] & 0b1000;
+        }
+    }
+
+    /// IDs of the packets, NAKs and whether the EOT arrived
+    fn frame(item: &Item) -> (Vec<u16>, &[Option<Vec<u16>>], bool) {
+        let Item::Frame {
+            packets, naks, eot, ..
+        } = item
+        else {
+            panic!("not a frame");
+        };
+        (packets.iter().map(|packet| packet.id).collect(), naks, *eot)
+    }
+
+    #[test]
+    fn len_counts_the_nibbles_left() {
+        for count in 1..=4 {
+            let mut queue = SendQueue::with_preamble(4);
+            queue.push(Frame::from_transmission(data(count)));
+            queue.push(Frame::from_transmission(Transmission::ack()));
+            let expected = 8
+                + wire_len(data(count).symbol_count())
+                + wire_len(Transmission::ack().symbol_count());
+            assert_eq!(queue.len(), expected);
+            assert_eq!(drain(&mut queue).len(), expected);
+        }
+    }
+
+    #[test]
+    fn clock_alternates_across_frames() {
+        let mut queue = SendQueue::with_preamble(4);
+        // frames of odd and even symbol counts leave the clock high or low
+        let symbols: Vec<usize> = (1..=4).map(|count| data(count).symbol_count()).collect();
+        assert!(symbols.iter().any(|count| count % 2 == 1));
+        assert!(symbols.iter().any(|count| count % 2 == 0));
+        for count in 1..=4 {
+            queue.push(Frame::from_transmission(data(count)));
+        }
+        let wire = drain(&mut queue);
+        assert_clock_alternates(&wire, 4);
+
+        let items = dissect(&wire);
+        assert!(matches!(items[0], Item::Idle { at: 0, nibbles: 8 }));
+        for (count, item) in (1..=4).zip(&items[1..]) {
+            assert_eq!(frame(item), ((1..=count).collect(), &[][..], true));
+        }
+        assert_eq!(items.len(), 5);
+    }
+
+    #[test]
+    fn nak_and_resend_join_the_frame() {
+        // one resent packet flips the frame from an even to an odd number of symbols
+        assert_eq!(data(3).packets[0].to_binary().len() % 2, 1);
+        for resent in [vec![2], vec![1, 2]] {
+            let mut queue = SendQueue::with_preamble(4);
+            queue.push(Frame::from_transmission(data(3)));
+            queue.push(Frame::from_transmission(Transmission::ack()));
+            // into the transmission header
+            let mut wire: Vec<u8> = (0..40).map(|_| pop(&mut queue)).collect();
+
+            queue.submit(Outgoing::Control(Nak::new(vec![7, 9]).to_binary()));
+            let mut resend = data(3);
+            resend
+                .packets
+                .retain(|packet| resent.contains(&packet.header.id));
+            queue.submit(Outgoing::Resend(resend));
+            wire.extend(drain(&mut queue));
+            assert_clock_alternates(&wire, 4);
+
+            let items = dissect(&wire);
+            assert_eq!(items.len(), 3);
+            let (packets, naks, eot) = frame(&items[1]);
+            assert_eq!(packets, [resent, vec![1, 2, 3]].concat());
+            assert_eq!(naks, [Some(vec![7, 9])]);
+            assert!(eot);
+            assert_eq!(frame(&items[2]), (vec![], &[][..], true));
+        }
+    }
+
+    #[test]
+    fn nak_between_frames_goes_out_on_its_own() {
+        let mut queue = SendQueue::with_preamble(4);
+        queue.submit(Outgoing::Control(Nak::new(vec![3]).to_binary()));
+        queue.push(Frame::from_transmission(data(1)));
+        let wire = drain(&mut queue);
+        assert_clock_alternates(&wire, 4);
+
+        let items = dissect(&wire);
+        assert_eq!(items.len(), 3);
+        assert!(matches!(&items[1], Item::Nak { ids: Some(ids), .. } if ids == &[3]));
+        assert_eq!(frame(&items[2]), (vec![1], &[][..], true));
+    }
+
+    #[test]
+    fn spliced_frame_follows_the_current_one() {
+        let mut queue = SendQueue::new();
+        queue.push(Frame::from_transmission(data(2)));
+        queue.push(Frame::from_transmission(data(3)));
+        let mut wire = vec![pop(&mut queue)];
+        queue.splice(Frame::from_transmission(Transmission::ack()));
+        wire.extend(drain(&mut queue));
+        assert_clock_alternates(&wire, 0);
+
+        let packets: Vec<_> = dissect(&wire)
+            .iter()
+            .map(frame)
+            .map(|(ids, ..)| ids)
+            .collect();
+        assert_eq!(packets, [vec![1, 2], vec![], vec![1, 2, 3]]);
+    }
+}
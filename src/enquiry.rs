@@ -0,0 +1,242 @@
+//! Payload of enquiry packets: the IDs of the packets to resend.
+//!
+//! Every packet is self-contained, a lost enquiry packet only loses its own IDs.
+//! The first byte tells the encoding:
+//! - `RANGES`: `(first id, count)` pairs, both `u16` little endian
+//! - `BITMAP`: first id and bit count (`u16` LE each), then one bit per ID starting at the
+//!   first one (LSB first), where runs of `0x00`/`0xFF` bytes are stored as `[byte, run length]`
+
+use std::collections::BTreeSet;
+
+const RANGES: u8 = 1;
+const BITMAP: u8 = 2;
+
+/// Splits `ids` into packet payloads of at most `max_len` bytes,
+/// each one using whichever encoding is smaller for its IDs
+pub fn encode_ids(ids: &[u16], max_len: usize) -> Vec<Vec<u8>> {
+    let ids: Vec<u16> = ids
+        .iter()
+        .copied()
+        .collect::<BTreeSet<u16>>()
+        .into_iter()
+        .collect();
+    let mut payloads = Vec::new();
+    let mut rest = &ids[..];
+    while !rest.is_empty() {
+        // largest prefix that still fits, a single ID always does
+        let (mut lo, mut hi) = (1, rest.len());
+        while lo < hi {
+            let mid = (lo + hi).div_ceil(2);
+            if encode_best(&rest[..mid]).len() <= max_len {
+                lo = mid;
+            } else {
+                hi = mid - 1;
+            }
+        }
+        payloads.push(encode_best(&rest[..lo]));
+        rest = &rest[lo..];
+    }
+    payloads
+}
+
+/// IDs of one enquiry packet, `None` if the payload is malformed
+pub fn decode_ids(payload: &[u8]) -> Option<Vec<u16>> {
+    let (&tag, body) = payload.split_first()?;
+    match tag {
+        RANGES => decode_ranges(body),
+        BITMAP => decode_bitmap(body),
+        _ => None,
+    }
+}
+
+/// `ids` must be sorted and free of duplicates
+fn encode_best(ids: &[u16]) -> Vec<u8> {
+    let ranges = encode_ranges(ids);
+    match encode_bitmap(ids) {
+        Some(bitmap) if bitmap.len() < ranges.len() => bitmap,
+        _ => ranges,
+    }
+}
+
+fn encode_ranges(ids: &[u16]) -> Vec<u8> {
+    let mut out = vec![RANGES];
+    let mut i = 0;
+    while i < ids.len() {
+        let first = ids[i];
+        let mut count: u16 = 1;
+        while i + (count as usize) < ids.len()
+            && ids[i + count as usize] == first.wrapping_add(count)
+            && count < u16::MAX
+        {
+            count += 1;
+        }
+        out.extend(first.to_le_bytes());
+        out.extend(count.to_le_bytes());
+        i += count as usize;
+    }
+    out
+}
+
+fn decode_ranges(body: &[u8]) -> Option<Vec<u16>> {
+    if !body.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut ids = Vec::new();
+    for range in body.chunks(4) {
+        let first = u16::from_le_bytes([range[0], range[1]]);
+        let count = u16::from_le_bytes([range[2], range[3]]);
+        ids.extend((0..count).map(|offset| first.wrapping_add(offset)));
+    }
+    Some(ids)
+}
+
+/// `None` if the IDs span more bits than the `u16` bit count can tell
+fn encode_bitmap(ids: &[u16]) -> Option<Vec<u8>> {
+    let (Some(&first), Some(&last)) = (ids.first(), ids.last()) else {
+        return Some(vec![BITMAP, 0, 0, 0, 0]);
+    };
+    let bits = (last - first) as usize + 1;
+    if bits > u16::MAX as usize {
+        return None;
+    }
+    let mut bitmap = vec![0u8; bits.div_ceil(8)];
+    for &id in ids {
+        let bit = (id - first) as usize;
+        bitmap[bit / 8] |= 1 << (bit % 8);
+    }
+
+    let mut out = vec![BITMAP];
+    out.extend(first.to_le_bytes());
+    out.extend((bits as u16).to_le_bytes());
+    let mut i = 0;
+    while i < bitmap.len() {
+        let byte = bitmap[i];
+        if byte == 0x00 || byte == 0xFF {
+            let mut run = 1;
+            while i + run < bitmap.len() && bitmap[i + run] == byte && run < u8::MAX as usize {
+                run += 1;
+            }
+            out.push(byte);
+            out.push(run as u8);
+            i += run;
+        } else {
+            out.push(byte);
+            i += 1;
+        }
+    }
+    Some(out)
+}
+
+fn decode_bitmap(body: &[u8]) -> Option<Vec<u16>> {
+    let first = u16::from_le_bytes([*body.first()?, *body.get(1)?]);
+    let bits = u16::from_le_bytes([*body.get(2)?, *body.get(3)?]) as usize;
+
+    let mut bitmap = Vec::with_capacity(bits.div_ceil(8));
+    let mut i = 4;
+    while i < body.len() {
+        let byte = body[i];
+        if byte == 0x00 || byte == 0xFF {
+            let run = *body.get(i + 1)?;
+            bitmap.extend(std::iter::repeat_n(byte, run as usize));
+            i += 2;
+        } else {
+            bitmap.push(byte);
+            i += 1;
+        }
+    }
+    if bitmap.len() != bits.div_ceil(8) {
+        return None;
+    }
+
+    Some(
+        (0..bits)
+            .filter(|bit| bitmap[bit / 8] & (1 << (bit % 8)) != 0)
+            .map(|bit| first.wrapping_add(bit as u16))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes with `max_len` and decodes every payload again
+    fn round_trip(ids: &[u16], max_len: usize) -> Vec<u16> {
+        let payloads = encode_ids(ids, max_len);
+        for payload in &payloads {
+            assert!(payload.len() <= max_len, "{} > {max_len}", payload.len());
+        }
+        payloads
+            .iter()
+            .flat_map(|payload| decode_ids(payload).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn empty() {
+        assert!(encode_ids(&[], 64).is_empty());
+        assert_eq!(decode_ids(&encode_best(&[])), Some(Vec::new()));
+    }
+
+    #[test]
+    fn single() {
+        assert_eq!(round_trip(&[42], 64), [42]);
+        assert_eq!(encode_best(&[42])[0], RANGES);
+    }
+
+    #[test]
+    fn contiguous_range() {
+        let ids: Vec<u16> = (100..=600).collect();
+        assert_eq!(round_trip(&ids, 64), ids);
+        let payload = encode_best(&ids);
+        assert_eq!(payload[0], RANGES);
+        assert_eq!(payload.len(), 5);
+    }
+
+    #[test]
+    fn sparse() {
+        let ids: Vec<u16> = (1..2000).step_by(3).collect();
+        assert_eq!(round_trip(&ids, 64), ids);
+        assert_eq!(encode_best(&ids)[0], BITMAP);
+    }
+
+    #[test]
+    fn unsorted_with_duplicates() {
+        assert_eq!(round_trip(&[9, 3, 9, 4, 3, 5], 64), [3, 4, 5, 9]);
+    }
+
+    #[test]
+    fn u16_max() {
+        assert_eq!(round_trip(&[u16::MAX], 64), [u16::MAX]);
+        assert_eq!(round_trip(&[0, 1, u16::MAX], 64), [0, 1, u16::MAX]);
+        let all: Vec<u16> = (0..=u16::MAX).collect();
+        assert_eq!(round_trip(&all, 64), all);
+        // a bitmap would be smallest, but the span is one bit more than it can count
+        let every_other: Vec<u16> = (0..=u16::MAX).step_by(2).chain([u16::MAX]).collect();
+        assert_eq!(encode_bitmap(&every_other), None);
+        assert_eq!(round_trip(&every_other, 10_000), every_other);
+    }
+
+    #[test]
+    fn picks_the_smaller_encoding() {
+        // two ranges take 9 bytes, the bitmap 6
+        let dense_gap = [1, 2, 3, 5, 6, 7, 8];
+        assert_eq!(encode_best(&dense_gap)[0], BITMAP);
+        assert!(encode_best(&dense_gap).len() < encode_ranges(&dense_gap).len());
+        // far apart: two ranges beat a bitmap of mostly zero runs
+        let far_apart = [1, 60_000];
+        assert_eq!(encode_best(&far_apart)[0], RANGES);
+        assert!(encode_best(&far_apart).len() < encode_bitmap(&far_apart).unwrap().len());
+    }
+
+    #[test]
+    fn malformed() {
+        assert_eq!(decode_ids(&[]), None);
+        assert_eq!(decode_ids(&[7, 1, 0, 1, 0]), None);
+        assert_eq!(decode_ids(&[RANGES, 1, 0, 1]), None);
+        // 16 bits announced, one byte of bitmap
+        assert_eq!(decode_ids(&[BITMAP, 0, 0, 16, 0, 0xAA]), None);
+        // run without its length
+        assert_eq!(decode_ids(&[BITMAP, 0, 0, 8, 0, 0xFF]), None);
+    }
+}
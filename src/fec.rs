@@ -0,0 +1,250 @@
+//! Forward error correction of packets, exchangeable per transmission.
+//!
+//! The transmission header announces the scheme its packets use, the header itself,
+//! NAKs and the outer code always use Reed-Solomon.
+
+use reed_solomon::{Decoder, Encoder};
+
+pub trait Fec: Send + Sync {
+    /// ECC bytes for `data`
+    fn encode(&self, data: &[u8]) -> Vec<u8>;
+
+    /// Corrects `data` and `ecc` in place. `erasures` are positions in `data` followed by `ecc`
+    /// known to be bad. Returns the number of corrected bytes, `None` if beyond repair.
+    fn decode(&self, data: &mut [u8], ecc: &mut [u8], erasures: &[u8]) -> Option<usize>;
+
+    /// Number of ECC bytes `encode` returns for `data_len` bytes
+    fn overhead(&self, data_len: usize) -> usize;
+}
+
+/// Scheme of the packets as signalled in the transmission header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FecScheme {
+    None,
+    /// ECC length in percent of header and data
+    ReedSolomon(u8),
+    Hamming,
+}
+
+const SCHEME_NONE: u8 = 0;
+const SCHEME_REED_SOLOMON: u8 = 1;
+const SCHEME_HAMMING: u8 = 2;
+
+impl FecScheme {
+    pub fn codec(self) -> Box<dyn Fec> {
+        match self {
+            Self::None => Box::new(NoFec),
+            Self::ReedSolomon(percent) => Box::new(ReedSolomon::Rate(percent)),
+            Self::Hamming => Box::new(Hamming),
+        }
+    }
+
+    /// Scheme and parameter
+    pub fn to_bytes(self) -> [u8; 2] {
+        match self {
+            Self::None => [SCHEME_NONE, 0],
+            Self::ReedSolomon(percent) => [SCHEME_REED_SOLOMON, percent],
+            Self::Hamming => [SCHEME_HAMMING, 0],
+        }
+    }
+
+    /// `None` for an unknown scheme
+    pub fn from_bytes(bytes: [u8; 2]) -> Option<Self> {
+        match bytes {
+            [SCHEME_NONE, _] => Some(Self::None),
+            [SCHEME_REED_SOLOMON, percent] if percent > 0 => Some(Self::ReedSolomon(percent)),
+            [SCHEME_HAMMING, _] => Some(Self::Hamming),
+            _ => None,
+        }
+    }
+}
+
+impl Default for FecScheme {
+    /// About the redundancy packets had before the scheme was selectable
+    fn default() -> Self {
+        Self::ReedSolomon(130)
+    }
+}
+
+/// No ECC at all, damage goes unnoticed
+pub struct NoFec;
+
+impl Fec for NoFec {
+    fn encode(&self, _data: &[u8]) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn decode(&self, _data: &mut [u8], _ecc: &mut [u8], _erasures: &[u8]) -> Option<usize> {
+        Some(0)
+    }
+
+    fn overhead(&self, _data_len: usize) -> usize {
+        0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReedSolomon {
+    /// ECC length in percent of the data
+    Rate(u8),
+    /// Fixed number of ECC bytes
+    Fixed(usize),
+}
+
+impl Fec for ReedSolomon {
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        Encoder::new(self.overhead(data.len()))
+            .encode(data)
+            .ecc()
+            .to_vec()
+    }
+
+    /// The ECC length is taken from `ecc` as it arrived
+    fn decode(&self, data: &mut [u8], ecc: &mut [u8], erasures: &[u8]) -> Option<usize> {
+        let mut msg = data.to_vec();
+        msg.extend_from_slice(ecc);
+        if msg.len() > u8::MAX as usize {
+            return None;
+        }
+        // without the erasures if that fails, in case they were not bad after all
+        let decoder = Decoder::new(ecc.len());
+        let (buffer, errors) = if erasures.is_empty() || erasures.len() > ecc.len() {
+            decoder.correct_err_count(&msg, None)
+        } else {
+            decoder
+                .correct_err_count(&msg, Some(erasures))
+                .or_else(|_| decoder.correct_err_count(&msg, None))
+        }
+        .ok()?;
+        data.copy_from_slice(buffer.data());
+        ecc.copy_from_slice(buffer.ecc());
+        Some(errors)
+    }
+
+    fn overhead(&self, data_len: usize) -> usize {
+        match *self {
+            Self::Rate(percent) => data_len * percent as usize / 100,
+            Self::Fixed(len) => len,
+        }
+    }
+}
+
+/// Extended Hamming(12,8) per byte: four parity bits and an overall parity bit
+/// in one ECC byte each, corrects one flipped bit and detects two.
+/// Cheap, but every byte stands alone, so erasures can't be used.
+pub struct Hamming;
+
+/// Codeword positions 1..=12 of the data bits, the powers of two hold the parity bits
+const DATA_POSITIONS: [u32; 8] = [3, 5, 6, 7, 9, 10, 11, 12];
+
+impl Hamming {
+    /// XOR of the positions of all set data bits
+    fn syndrome(byte: u8) -> u8 {
+        DATA_POSITIONS
+            .iter()
+            .enumerate()
+            .filter(|(bit, _)| byte >> bit & 1 == 1)
+            .fold(0, |syndrome, (_, &position)| syndrome ^ position as u8)
+    }
+
+    fn parity(byte: u8) -> u8 {
+        let syndrome = Self::syndrome(byte);
+        let overall = (byte.count_ones() + syndrome.count_ones()) as u8 & 1;
+        syndrome | overall << 4
+    }
+}
+
+impl Fec for Hamming {
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        data.iter().map(|&byte| Self::parity(byte)).collect()
+    }
+
+    fn decode(&self, data: &mut [u8], ecc: &mut [u8], _erasures: &[u8]) -> Option<usize> {
+        if data.len() != ecc.len() {
+            return None;
+        }
+        let mut corrected = 0;
+        for (byte, parity) in data.iter_mut().zip(ecc.iter_mut()) {
+            let syndrome = Self::syndrome(*byte) ^ (*parity & 0xF);
+            let odd = (byte.count_ones() + (*parity & 0x1F).count_ones()) % 2 == 1;
+            match (syndrome, odd) {
+                (0, false) => continue,
+                // two bits flipped
+                (_, false) => return None,
+                (0, true) => *parity ^= 1 << 4,
+                (1 | 2 | 4 | 8, true) => *parity ^= syndrome,
+                (_, true) => {
+                    let bit = DATA_POSITIONS
+                        .iter()
+                        .position(|&position| position == syndrome as u32)?;
+                    *byte ^= 1 << bit;
+                }
+            }
+            corrected += 1;
+        }
+        Some(corrected)
+    }
+
+    fn overhead(&self, data_len: usize) -> usize {
+        data_len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Data byte and parity byte with bit `bit` of the 13 bit codeword flipped,
+    /// bits 0..8 are the data bits and 8..13 the parity bits
+    fn flip(byte: u8, parity: u8, bit: u32) -> (u8, u8) {
+        match bit {
+            0..8 => (byte ^ 1 << bit, parity),
+            _ => (byte, parity ^ 1 << (bit - 8)),
+        }
+    }
+
+    #[test]
+    fn hamming_corrects_every_single_bit_error() {
+        for byte in 0..=u8::MAX {
+            let parity = Hamming.encode(&[byte])[0];
+            for bit in 0..13 {
+                let (data, ecc) = flip(byte, parity, bit);
+                let (mut data, mut ecc) = ([data], [ecc]);
+                assert_eq!(
+                    Hamming.decode(&mut data, &mut ecc, &[]),
+                    Some(1),
+                    "{byte} bit {bit}"
+                );
+                assert_eq!((data[0], ecc[0]), (byte, parity), "{byte} bit {bit}");
+            }
+        }
+    }
+
+    #[test]
+    fn hamming_detects_every_double_bit_error() {
+        for byte in 0..=u8::MAX {
+            let parity = Hamming.encode(&[byte])[0];
+            for first in 0..13 {
+                for second in first + 1..13 {
+                    let (data, ecc) = flip(byte, parity, first);
+                    let (data, ecc) = flip(data, ecc, second);
+                    let (mut data, mut ecc) = ([data], [ecc]);
+                    assert_eq!(
+                        Hamming.decode(&mut data, &mut ecc, &[]),
+                        None,
+                        "{byte} bits {first} and {second}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn hamming_leaves_clean_bytes_alone() {
+        let data: Vec<u8> = (0..=u8::MAX).collect();
+        let mut ecc = Hamming.encode(&data);
+        let mut received = data.clone();
+        assert_eq!(Hamming.decode(&mut received, &mut ecc, &[]), Some(0));
+        assert_eq!(received, data);
+    }
+}
@@ -0,0 +1,148 @@
+//! Block interleaver across packets: `depth` packets form the rows of a block, which is sent
+//! column by column. A burst on the wire then costs every packet of the block a few bytes
+//! instead of wiping out one packet, and each packet's FEC repairs its share.
+//!
+//! Every row is `row_len` symbols long, shorter packets and missing rows of the last block
+//! are filled with zero bytes, which the receiver cuts off by the packet's declared size.
+
+use crate::controls::Symbol;
+
+/// Symbol filling up short rows
+pub const FILLER: Symbol = Symbol::Data(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interleaver {
+    /// Packets per block
+    pub depth: u8,
+    /// Symbols per packet incl. filler, the longest packet of the transmission
+    pub row_len: u16,
+}
+
+impl Interleaver {
+    /// `None` for a combination that can't interleave, e.g. from a damaged header
+    pub fn try_new(depth: u8, row_len: u16) -> Option<Self> {
+        (depth > 1 && row_len > 0).then_some(Self { depth, row_len })
+    }
+
+    /// Symbols of a whole block
+    pub fn block_len(&self) -> usize {
+        self.depth as usize * self.row_len as usize
+    }
+
+    /// Interleaves up to `depth` packets, given as their symbols, into one block
+    pub fn interleave(&self, rows: &[Vec<Symbol>]) -> Vec<Symbol> {
+        assert!(rows.len() <= self.depth as usize, "more rows than depth");
+        (0..self.row_len as usize)
+            .flat_map(|column| {
+                (0..self.depth as usize).map(move |row| {
+                    rows.get(row)
+                        .and_then(|row| row.get(column))
+                        .copied()
+                        .unwrap_or(FILLER)
+                })
+            })
+            .collect()
+    }
+
+    /// Rows of `block` one after another, a block cut short is completed with `missing`
+    pub fn deinterleave<T: Clone>(&self, block: &[T], missing: T) -> Vec<T> {
+        let depth = self.depth as usize;
+        let mut rows = Vec::with_capacity(self.block_len());
+        for row in 0..depth {
+            for column in 0..self.row_len as usize {
+                rows.push(block.get(column * depth + row).unwrap_or(&missing).clone());
+            }
+        }
+        rows
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        encoder::clock_symbol,
+        fec::FecScheme,
+        protocol::{ProtocolDecoder, Transmission},
+        utilities::squash_nibbles,
+    };
+
+    fn row(len: usize, seed: u8) -> Vec<Symbol> {
+        (0..len)
+            .map(|i| Symbol::Data(seed.wrapping_mul(31) ^ i as u8))
+            .collect()
+    }
+
+    #[test]
+    fn short_rows_and_the_last_block_are_filled() {
+        let interleaver = Interleaver::try_new(3, 4).unwrap();
+        let rows = [row(4, 1), row(2, 2)];
+        let block = interleaver.interleave(&rows);
+        assert_eq!(block.len(), interleaver.block_len());
+        assert_eq!(block[..3], [rows[0][0], rows[1][0], FILLER]);
+        assert_eq!(block[6..9], [rows[0][2], FILLER, FILLER]);
+
+        let deinterleaved = interleaver.deinterleave(&block, Symbol::Data(0xEE));
+        let expected = [
+            row(4, 1),
+            [row(2, 2), vec![FILLER; 2]].concat(),
+            vec![FILLER; 4],
+        ]
+        .concat();
+        assert_eq!(deinterleaved, expected);
+    }
+
+    #[test]
+    fn block_cut_short_is_completed_with_missing() {
+        let interleaver = Interleaver::try_new(2, 3).unwrap();
+        let block: Vec<Option<Symbol>> = interleaver
+            .interleave(&[row(3, 1), row(3, 2)])
+            .into_iter()
+            .map(Some)
+            .take(3)
+            .collect();
+        let rows = interleaver.deinterleave(&block, None);
+        let (first, second) = (row(3, 1), row(3, 2));
+        assert_eq!(
+            rows,
+            [
+                Some(first[0]),
+                Some(first[1]),
+                None,
+                Some(second[0]),
+                None,
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn interleaved_transmission_round_trips() {
+        // 5 packets of uneven sizes in blocks of 2, the last block holds a single packet
+        let chunks: Vec<Vec<u8>> = [9, 3, 17, 1, 12]
+            .iter()
+            .map(|&len| (0..len).map(|i| i * 13 + len).collect())
+            .collect();
+        let transmission = Transmission::from_chunks(chunks.clone(), FecScheme::default(), None)
+            .unwrap()
+            .interleaved(2);
+        let interleaver = transmission.header.interleaver.unwrap();
+        assert_eq!(interleaver.depth, 2);
+
+        let mut nibbles = Vec::new();
+        for (i, symbol) in transmission.into_segments().flatten().enumerate() {
+            nibbles.extend(clock_symbol(symbol, i as u8 & 1));
+        }
+        let decoded = ProtocolDecoder::new(squash_nibbles(&nibbles))
+            .decode()
+            .unwrap();
+        assert_eq!(decoded.header.interleaver, Some(interleaver));
+        let received: Vec<(u16, Vec<u8>)> = decoded
+            .packets
+            .into_iter()
+            .map(|packet| (packet.header.id, packet.data))
+            .collect();
+        let sent: Vec<(u16, Vec<u8>)> = (1..).zip(chunks).collect();
+        assert_eq!(received, sent);
+    }
+}
@@ -0,0 +1,221 @@
+//! JSON as far as the transfer reports and the JSON lines log need it: strings escaped for
+//! output written by hand, and a small parser to read such output back.
+
+use std::fmt::Write;
+
+/// `text` as the inside of a JSON string
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A parsed value, objects keep their fields in order
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+/// First value in `text`, `None` if it is no valid JSON. What follows the value is ignored.
+pub fn parse(text: &str) -> Option<Json> {
+    Parser { rest: text }.value()
+}
+
+struct Parser<'a> {
+    rest: &'a str,
+}
+
+impl Parser<'_> {
+    fn eat(&mut self, token: &str) -> bool {
+        self.rest = self.rest.trim_start();
+        match self.rest.strip_prefix(token) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn value(&mut self) -> Option<Json> {
+        self.rest = self.rest.trim_start();
+        if self.eat("null") {
+            Some(Json::Null)
+        } else if self.eat("true") {
+            Some(Json::Bool(true))
+        } else if self.eat("false") {
+            Some(Json::Bool(false))
+        } else if self.eat("\"") {
+            self.string().map(Json::String)
+        } else if self.eat("[") {
+            let mut items = Vec::new();
+            if !self.eat("]") {
+                loop {
+                    items.push(self.value()?);
+                    if self.eat("]") {
+                        break;
+                    }
+                    if !self.eat(",") {
+                        return None;
+                    }
+                }
+            }
+            Some(Json::Array(items))
+        } else if self.eat("{") {
+            let mut fields = Vec::new();
+            if !self.eat("}") {
+                loop {
+                    if !self.eat("\"") {
+                        return None;
+                    }
+                    let key = self.string()?;
+                    if !self.eat(":") {
+                        return None;
+                    }
+                    fields.push((key, self.value()?));
+                    if self.eat("}") {
+                        break;
+                    }
+                    if !self.eat(",") {
+                        return None;
+                    }
+                }
+            }
+            Some(Json::Object(fields))
+        } else {
+            let end = self
+                .rest
+                .find(|c: char| !matches!(c, '0'..='9' | '-' | '+' | '.' | 'e' | 'E'))
+                .unwrap_or(self.rest.len());
+            let number = self.rest[..end].parse().ok()?;
+            self.rest = &self.rest[end..];
+            Some(Json::Number(number))
+        }
+    }
+
+    /// Rest of a string after its opening quote
+    fn string(&mut self) -> Option<String> {
+        let mut string = String::new();
+        let mut chars = self.rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.rest = &self.rest[i + 1..];
+                    return Some(string);
+                }
+                '\\' => match chars.next()?.1 {
+                    'n' => string.push('\n'),
+                    't' => string.push('\t'),
+                    'r' => string.push('\r'),
+                    'u' => {
+                        let hex: String = (0..4)
+                            .filter_map(|_| chars.next())
+                            .map(|(_, c)| c)
+                            .collect();
+                        string.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                    }
+                    c => string.push(c),
+                },
+                c => string.push(c),
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(text: &str) -> Option<Json> {
+        parse(&format!("\"{}\"", escape(text)))
+    }
+
+    #[test]
+    fn escaped_strings_round_trip() {
+        for text in [
+            "",
+            "plain",
+            "\"quoted\"",
+            "back\\slash \\\"",
+            "line\nbreak\ttab\rreturn",
+            "\u{0}\u{1b}[31mred\u{7f}",
+            "ümlaut → ✓",
+        ] {
+            assert_eq!(
+                string(text),
+                Some(Json::String(text.to_string())),
+                "{text:?}"
+            );
+        }
+        assert_eq!(escape("a\"b\\c\n"), "a\\\"b\\\\c\\u000a");
+    }
+
+    #[test]
+    fn numbers_round_trip() {
+        for number in [0.0, 1.0, -1.0, 42.0, 0.125, -3.5e-7, 1e300, u64::MAX as f64] {
+            assert_eq!(parse(&number.to_string()), Some(Json::Number(number)));
+            assert_eq!(parse(&format!("{number:e}")), Some(Json::Number(number)));
+        }
+        // as the reports write them
+        assert_eq!(parse(&format!("{:.3}", 1.5)), Some(Json::Number(1.5)));
+        assert_eq!(parse("12abc"), Some(Json::Number(12.0)));
+        assert_eq!(parse("-"), None);
+        assert_eq!(parse("1.2.3"), None);
+    }
+
+    #[test]
+    fn nested_values() {
+        let text = r#" {"a": [1, [true, false, null], {"b": {}}], "c": "x", "d": []} "#;
+        let expected = Json::Object(vec![
+            (
+                "a".to_string(),
+                Json::Array(vec![
+                    Json::Number(1.0),
+                    Json::Array(vec![Json::Bool(true), Json::Bool(false), Json::Null]),
+                    Json::Object(vec![("b".to_string(), Json::Object(Vec::new()))]),
+                ]),
+            ),
+            ("c".to_string(), Json::String("x".to_string())),
+            ("d".to_string(), Json::Array(Vec::new())),
+        ]);
+        assert_eq!(parse(text), Some(expected));
+        // keys are escaped like any string
+        let key = format!(r#"{{"{}": 1}}"#, escape("k\"ey"));
+        assert_eq!(
+            parse(&key),
+            Some(Json::Object(vec![("k\"ey".to_string(), Json::Number(1.0))]))
+        );
+    }
+
+    #[test]
+    fn malformed() {
+        for text in [
+            "",
+            "[1, 2",
+            "[1 2]",
+            "{\"a\" 1}",
+            "{a: 1}",
+            "{\"a\": 1,}",
+            "\"open",
+            "\"\\u12\"",
+            "\"\\ud800\"",
+        ] {
+            assert_eq!(parse(text), None, "{text:?}");
+        }
+    }
+}
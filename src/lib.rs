@@ -1,6 +1,27 @@
 // pub mod arduino;
+pub mod analyzer;
+pub mod arq;
+pub mod capture;
+pub mod clock;
 pub mod consts;
 pub mod controls;
+pub mod dashboard;
+pub mod dissect;
+pub mod encoder;
+pub mod enquiry;
+pub mod fec;
+pub mod interleave;
+pub mod json;
+pub mod link;
+pub mod log;
 pub mod macros;
+pub mod monitor;
+pub mod outer;
 pub mod protocol;
+pub mod report;
+pub mod scheduler;
+pub mod selftest;
+pub mod session;
 pub mod utilities;
+pub mod vcd;
+pub mod waveform;
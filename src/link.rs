@@ -0,0 +1,80 @@
+use std::io;
+use std::sync::{mpsc, Arc, Mutex};
+
+/// A physical connection that moves single nibbles (clock bit + 3 data bits)
+pub trait Link: Send {
+    /// Puts one nibble on the outgoing lines
+    fn send(&mut self, nibble: u8) -> io::Result<()>;
+
+    /// Next nibble seen on the incoming lines, errors with `TimedOut` if there was none
+    fn receive(&mut self) -> io::Result<u8>;
+
+    /// Second handle to the same connection, so sending and receiving can run on their own threads
+    fn try_clone(&self) -> io::Result<Box<dyn Link>>;
+}
+
+/// `true` for errors that only mean "nothing arrived yet"
+pub fn is_timeout(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+    )
+}
+
+/// Both ends of a connection in memory, what one end sends the other receives.
+/// Like the Arduino only nibbles that change the clock line are reported, and receiving
+/// never blocks, so simulations can step both ends on one thread.
+pub fn loopback() -> (LoopbackLink, LoopbackLink) {
+    let (a_tx, a_rx) = mpsc::channel();
+    let (b_tx, b_rx) = mpsc::channel();
+    (
+        LoopbackLink {
+            tx: a_tx,
+            rx: Arc::new(Mutex::new((b_rx, 0))),
+        },
+        LoopbackLink {
+            tx: b_tx,
+            rx: Arc::new(Mutex::new((a_rx, 0))),
+        },
+    )
+}
+
+/// One end of a [`loopback`] connection, clones share it
+pub struct LoopbackLink {
+    tx: mpsc::Sender<u8>,
+    /// Incoming lines and the clock level last reported
+    rx: Arc<Mutex<(mpsc::Receiver<u8>, u8)>>,
+}
+
+impl Link for LoopbackLink {
+    /// Nibbles sent to a dropped end are lost, like on an unplugged wire
+    fn send(&mut self, nibble: u8) -> io::Result<()> {
+        let _ = self.tx.send(nibble);
+        Ok(())
+    }
+
+    fn receive(&mut self) -> io::Result<u8> {
+        let mut rx = self.rx.lock().unwrap();
+        let (lines, clock) = &mut *rx;
+        loop {
+            match lines.try_recv() {
+                Ok(nibble) if nibble & 0b1000 != *clock => {
+                    *clock = nibble & 0b1000;
+                    return Ok(nibble);
+                }
+                Ok(_) => continue,
+                Err(mpsc::TryRecvError::Empty) => return Err(io::ErrorKind::WouldBlock.into()),
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    return Err(io::ErrorKind::BrokenPipe.into())
+                }
+            }
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Link>> {
+        Ok(Box::new(LoopbackLink {
+            tx: self.tx.clone(),
+            rx: Arc::clone(&self.rx),
+        }))
+    }
+}
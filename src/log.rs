@@ -0,0 +1,141 @@
+//! Leveled logging behind the `trace!`, `debug!`, `info!`, `warn!` and `error!` macros.
+//!
+//! Everything at or above the level goes to stderr, colored only if stderr is a terminal,
+//! and to an optional file as plain text or JSON lines. Messages that carry their own colors
+//! lose them wherever no terminal shows them. Logging never ends the process, failing to
+//! write a line is ignored.
+
+use std::fmt;
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+
+use ansi_term::Color::{Blue, Green, Purple, Red, Yellow};
+
+use crate::json::escape;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    const ALL: [Level; 5] = [
+        Level::Trace,
+        Level::Debug,
+        Level::Info,
+        Level::Warn,
+        Level::Error,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Trace => "TRACE",
+            Level::Debug => "DEBUG",
+            Level::Info => "INFO",
+            Level::Warn => "WARN",
+            Level::Error => "ERROR",
+        }
+    }
+
+    /// `steps` more verbose, negative for quieter, clamped to trace and error
+    pub fn shifted(self, steps: i32) -> Level {
+        let index = (self as i32 - steps).clamp(0, Level::ALL.len() as i32 - 1);
+        Level::ALL[index as usize]
+    }
+
+    fn paint(self) -> String {
+        let color = match self {
+            Level::Trace => Purple,
+            Level::Debug => Blue,
+            Level::Info => Green,
+            Level::Warn => Yellow,
+            Level::Error => Red,
+        };
+        color.bold().paint(self.name()).to_string()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Plain,
+    /// One JSON object per line with time, level, module and message
+    JsonLines,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+#[allow(clippy::type_complexity)]
+static FILE: Mutex<Option<(Box<dyn Write + Send>, Format)>> = Mutex::new(None);
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn level() -> Level {
+    Level::ALL[LEVEL.load(Ordering::Relaxed) as usize]
+}
+
+pub fn enabled(level: Level) -> bool {
+    level >= self::level()
+}
+
+/// Also writes every line to `out`, replacing an earlier file
+pub fn set_file(out: Box<dyn Write + Send>, format: Format) {
+    *FILE.lock().unwrap_or_else(|e| e.into_inner()) = Some((out, format));
+}
+
+/// What the macros call, `target` is the module the line comes from
+pub fn log(level: Level, target: &str, args: fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+    let message = args.to_string();
+    let plain = strip_ansi(&message);
+
+    let stderr = io::stderr();
+    let _ = if stderr.is_terminal() {
+        writeln!(stderr.lock(), "[{}] {message}", level.paint())
+    } else {
+        writeln!(stderr.lock(), "[{}] {plain}", level.name())
+    };
+
+    let mut file = FILE.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((out, format)) = file.as_mut() {
+        let time = chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, false);
+        let _ = match format {
+            Format::Plain => writeln!(out, "{time} {:<5} {target}: {plain}", level.name()),
+            Format::JsonLines => writeln!(
+                out,
+                "{{\"time\":\"{time}\",\"level\":\"{}\",\"target\":\"{}\",\"message\":\"{}\"}}",
+                level.name().to_lowercase(),
+                escape(target),
+                escape(&plain)
+            ),
+        };
+        let _ = out.flush();
+    }
+}
+
+/// Removes the color codes `ansi_term` puts into messages
+fn strip_ansi(text: &str) -> String {
+    let mut plain = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // ESC [ parameters final byte
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            plain.push(c);
+        }
+    }
+    plain
+}
@@ -5,36 +5,37 @@ macro_rules! nibble {
     };
 }
 
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        $crate::log::log($crate::log::Level::Trace, module_path!(), format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::log::log($crate::log::Level::Debug, module_path!(), format_args!($($arg)*))
+    };
+}
+
 #[macro_export]
 macro_rules! info {
-    ($fmt:expr $(, $args:expr)*) => {
-        eprintln!(
-            "[{}] {}",
-            "\x1b[32m\x1b[1mINFO\x1b[0m",
-            format!($fmt, $($args),*)
-        )
+    ($($arg:tt)*) => {
+        $crate::log::log($crate::log::Level::Info, module_path!(), format_args!($($arg)*))
     };
 }
 
 #[macro_export]
-macro_rules! error {
-    ($fmt:expr $(, $args:expr)*) => {
-        eprintln!(
-            "[{}] {}",
-            "\x1b[31m\x1b[1mERROR\x1b[0m",
-            format!($fmt, $($args),*)
-        )
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::log::log($crate::log::Level::Warn, module_path!(), format_args!($($arg)*))
     };
 }
 
 #[macro_export]
-macro_rules! fatal {
-    ($fmt:expr $(, $args:expr)*) => {
-        eprintln!(
-            "[{}] {}",
-            "\x1b[31m\x1b[1m\x1b[5mFATAL\x1b[0m",
-            format!($fmt, $($args),*)
-        );
-        std::process::exit(1);
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::log::log($crate::log::Level::Error, module_path!(), format_args!($($arg)*))
     };
 }
@@ -1,21 +1,37 @@
 use std::io::Write;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
 use std::{io, time::Duration};
 
 use ansi_term::Color::Yellow;
 use b15r::DdrPin::DDRA;
 use b15r::PortPin::PORTA;
 use b15r::{Port0, B15F};
-use indicatif::{ProgressBar, ProgressStyle};
-use reed_solomon::Decoder;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use serialport::{ClearBuffer, SerialPort};
 
-use v7::protocol::{ProtocolDecoder, State, Transmission};
-use v7::utilities::{
-    chunk_data, make_transmission, print_colored_byte, read_stdin_as_vec_u8, ready_for_send,
-    slice_data, start_and_end, u16_to_u8_vec,
-};
-use v7::{error, info};
+use v7::analyzer::{read_trace, DEFAULT_CHANNELS};
+use v7::arq::ArqConfig;
+use v7::capture::{self, Direction, RecordingLink};
+use v7::clock::{Clock, SystemClock};
+use v7::dissect::dissect;
+use v7::encoder::{Frame, Outgoing, SendQueue, PREAMBLE_PAIRS};
+use v7::fec::FecScheme;
+use v7::link::Link;
+use v7::log;
+use v7::outer::OuterCode;
+use v7::protocol::Transmission;
+use v7::report::{speed_table, Report};
+use v7::scheduler::{spawn_receiver, spawn_sender, NibbleScheduler};
+use v7::selftest::{self, Diagnosis, SelfTest};
+use v7::session::{Event, Session};
+#[allow(unused_imports)]
+use v7::utilities::print_colored_byte;
+use v7::utilities::{chunk_data, read_stdin_as_vec_u8};
+use v7::vcd::{write_vcd, Trace};
+use v7::waveform::Waveform;
+use v7::{debug, error, info};
 
 // TODO: 1 Packet pro Transmission
 
@@ -23,25 +39,69 @@ use v7::{error, info};
 const PORT_NAME: &str = "/dev/ttyUSB0";
 #[allow(dead_code)]
 const BAUD_RATE: u32 = 115_200;
-const TIMEOUT: u128 = 2000;
+const PORT_TIMEOUT: Duration = Duration::from_millis(100);
+const TIMEOUT: Duration = Duration::from_millis(2000);
+const MAX_RETRIES: u32 = 8;
 
 // Nano <-> Nano: 4ms
 // B15 <-> Nano: 29ms (15ms?)
-const CLK_DELAY: u128 = 4;
-
+const CLK_DELAY: Duration = Duration::from_millis(4);
+// pause of the receiver thread when no new nibble was there, well below `CLK_DELAY`
+const RECEIVE_POLL: Duration = Duration::from_millis(1);
+// how often the packet map is redrawn without anything new arriving
+const DASHBOARD_INTERVAL: Duration = Duration::from_millis(250);
+
+// data bytes per packet, packets longer than a codeword are split into several,
+// see `consts::MAX_SIZE`; `--chunk-size <bytes>` changes it for one transfer
 const CHUNK_SIZE: usize = 48;
+// parity packets after every group of data packets, `None` to send data packets only;
+// `--outer-code <data>/<parity>` turns it on for one transfer
+const OUTER_CODE: Option<OuterCode> = None;
+// ECC of every packet, e.g. `FecScheme::Hamming` or `FecScheme::ReedSolomon(100)`
+const FEC: FecScheme = FecScheme::ReedSolomon(130);
+// packets per interleaved block, a burst is spread over all of them; 1 to send them in order,
+// `--interleave <depth>` changes it for one transfer
+const INTERLEAVE_DEPTH: u8 = 1;
 
-#[allow(clippy::too_many_lines)]
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    ////////// init //////////
-    let mut state = State::Normal;
-    let mut transmission_packet_array: Vec<Vec<u8>> = (0..u16::MAX).map(|_| Vec::new()).collect();
-    let mut broken_ids: Vec<u16> = Vec::new();
+    let args = setup_logging(std::env::args().collect())?;
+    match args.get(1).map(String::as_str) {
+        Some("decode") => return decode(&args[2..]),
+        Some("replay") => return replay(args.get(2)),
+        Some("vcd") => return vcd(&args[2..]),
+        Some("wave") => return wave(&args[2..]),
+        Some("table") => return table(&args[2..]),
+        Some("selftest") => return selftest(),
+        _ => {}
+    }
+    // every nibble on the wire goes to this file, see `v7 decode` and `v7 replay`
+    let record = option(&args, "--record")?;
+    // statistics of the transfer as JSON once it ended, see `v7 table`
+    let report = option(&args, "--report")?;
+    let label = option(&args, "--label")?.map_or("Arduino-Arduino", String::as_str);
+    let outer_code = match option(&args, "--outer-code")? {
+        Some(code) => Some(parse_outer_code(code)?),
+        None => OUTER_CODE,
+    };
+    let interleave_depth = match option(&args, "--interleave")? {
+        Some(depth) => parse_interleave_depth(depth)?,
+        None => INTERLEAVE_DEPTH,
+    };
+    let chunk_size = match option(&args, "--chunk-size")? {
+        Some(size) => parse_chunk_size(size)?,
+        None => CHUNK_SIZE,
+    };
 
-    #[allow(dead_code)]
-    let mut clock = 0; // needed for b15
-                       // let mut drv = setup_b15();
-    let mut port = setup_nano();
+    ////////// init //////////
+    let clock = SystemClock;
+
+    // let link: Box<dyn Link> = Box::new(B15Link::new(setup_b15()));
+    let mut link: Box<dyn Link> = Box::new(NanoLink(setup_nano()));
+    if let Some(path) = record {
+        let file = std::fs::File::create(path)?;
+        link = Box::new(RecordingLink::new(link, Box::new(file), Arc::new(clock))?);
+        info!("Recording the wire to {path}");
+    }
 
     ////////// data setup //////////
 
@@ -55,163 +115,515 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // from file -> Transmission
     let data = read_stdin_as_vec_u8().unwrap();
+    let bytes_sent = data.len();
 
-    let chunked = chunk_data(data, CHUNK_SIZE);
+    let chunked = chunk_data(data, chunk_size);
 
-    let transmission = Transmission::new(make_transmission(chunked), false);
-    let mut transmission_bins = transmission.clone().to_binary();
+    let transmission = Transmission::from_chunks(chunked, FEC, outer_code)
+        .ok_or("the chunk size is too long for a packet or too short for 16 bit IDs")?
+        .interleaved(interleave_depth);
 
-    transmission_bins = ready_for_send(transmission_bins);
+    // TODO: iwann entfernen oder weniger
+    let mut send_queue = SendQueue::with_preamble(PREAMBLE_PAIRS);
+    send_queue.push(Frame::from_transmission(transmission.clone()));
 
-    for _ in 0..100 {
-        // TODO: iwann entfernen oder weniger
-        transmission_bins.insert(0, 0);
-        transmission_bins.insert(0, 0b1000);
-    }
-    let pb = ProgressBar::new((transmission_bins.len() - 1) as u64);
+    let bars = MultiProgress::new();
+    let pb = bars.add(ProgressBar::new((send_queue.len() - 1) as u64));
     pb.set_style(
         ProgressStyle::default_bar()
             .template("[{wide_bar}] [{percent}%] [{elapsed}|{eta}] [{bytes_per_sec}] [{pos}/{len}]")
             .unwrap()
             .progress_chars("=>-"),
     );
+    // what arrived of the peer's file, below the bar of what we sent
+    let dashboard = bars.add(ProgressBar::new_spinner());
+    dashboard.set_style(ProgressStyle::with_template("{msg}").unwrap());
+    let mut dashboard_drawn = clock.now();
 
-    ////////// timeout //////////
-    let mut start_timeout = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("Time went backwards")
-        .as_millis();
-
-    ////////// main loop //////////
-    let mut received: Vec<u8> = Vec::new();
+    ////////// threads //////////
+    let scheduler = Arc::new(Mutex::new(NibbleScheduler::new(send_queue, CLK_DELAY)));
+    let nibbles = spawn_receiver(link.try_clone()?, RECEIVE_POLL);
 
     read_stdin_as_vec_u8().expect("dumm"); // TODO: zum Testen
 
-    let mut previous_millis = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("Time went backwards")
-        .as_millis();
-
-    loop {
-        ////////// send //////////
-        let current_millis = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_millis();
-
-        // Check if the interval has elapsed
-        if current_millis - previous_millis >= CLK_DELAY {
-            previous_millis = current_millis;
-            if !transmission_bins.is_empty() {
-                let byte = transmission_bins.remove(0);
-
-                start_timeout = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .expect("Time went backwards")
-                    .as_millis();
+    let sent_pb = pb.clone();
+    let nibbles_sent = Arc::new(AtomicUsize::new(0));
+    let sent_count = Arc::clone(&nibbles_sent);
+    spawn_sender(link, Arc::clone(&scheduler), clock, move |_| {
+        sent_pb.inc(1);
+        sent_count.fetch_add(1, Ordering::Relaxed);
+    });
 
-                pb.suspend(|| {
-                    // send_b15(&mut drv, byte);
-                    send_nano(&mut port, byte);
-                });
-                pb.inc(1);
-            }
+    ////////// main loop //////////
+    let mut session = Session::new(transmission, chunk_size, arq_config());
+    let started = clock.now();
+    let write_report = |session: &Session| {
+        let Some(path) = report else {
+            return;
+        };
+        let report = Report::new(
+            label,
+            session.packet_map(),
+            clock.now() - started,
+            bytes_sent,
+            nibbles_sent.load(Ordering::Relaxed),
+        );
+        match std::fs::write(path, report.to_json()) {
+            Ok(()) => info!("Report written to {path}"),
+            Err(e) => error!("Could not write the report to {path}: {e}"),
         }
+    };
+    let mut reported = false;
 
-        ////////// receive //////////
-        let receiver: Result<u8, io::Error> = {
-            // receive_b15(&mut drv, &mut clock)
-            receive_nano(&mut port, 1)
+    loop {
+        let (last_sent, drained) = {
+            let scheduler = scheduler.lock().unwrap();
+            (scheduler.last_sent(), scheduler.queue().is_empty())
         };
+        if let Some(sent) = last_sent {
+            session.on_sent(sent, drained);
+        }
 
-        match receiver {
+        ////////// receive //////////
+        let mut outgoing = Vec::new();
+        let event = match nibbles.recv_timeout(CLK_DELAY) {
             Ok(byte) => {
-                start_timeout = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .expect("Time went backwards")
-                    .as_millis();
                 // pb.suspend(|| {
                 //     eprint!("Received:{byte:2?} - [");
                 //     print_colored_byte(byte);
+                //     eprintln!("]");
                 // });
-                received.push(byte);
-                if received.len() < 6 {
-                    continue;
-                }
-                let mut range = None;
+                pb.suspend(|| session.on_nibble(byte, clock.now(), &mut outgoing))
+            }
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => {
+                error!("Link closed");
+                write_report(&session);
+                return Err("link closed".into());
+            }
+        };
+
+        ////////// timeout //////////
+        if let Err(e) = pb.suspend(|| session.poll(clock.now(), &mut outgoing)) {
+            pb.abandon();
+            error!("Transfer failed: {e}");
+            write_report(&session);
+            return Err(e.into());
+        }
+
+        match &event {
+            Some(Event::Completed(result)) => {
                 pb.suspend(|| {
-                    range = start_and_end(&received);
+                    info!("Writing data to stdout...");
                 });
-                if let Some((start, end)) = range {
-                    let data = received.clone();
-                    received.clear();
-                    // INFO: this returns ids of packets that are not recoverable or missing
-                    pb.suspend(|| {
-                        broken_ids = auswertung(
-                            data,
-                            start,
-                            end,
-                            &mut transmission_packet_array,
-                            &transmission,
-                            &mut transmission_bins,
-                            &mut state,
-                        );
-                    });
-                    pb.set_position(0);
-                    pb.set_length(transmission_bins.len() as u64);
-
-                    if broken_ids.is_empty() {
-                        pb.finish();
-                    } else {
-                        pb.suspend(|| {
-                            info!("Need {} packets to be resent!", broken_ids.len());
-                        });
-                        let chunked = chunk_data(u16_to_u8_vec(broken_ids.clone()), CHUNK_SIZE);
-                        let transmission = Transmission::new(make_transmission(chunked), true);
-                        transmission_bins.extend(ready_for_send(transmission.clone().to_binary()));
-                        state = State::WaitingForResponse;
-                        pb.set_position(0);
-                        pb.set_length(transmission_bins.len() as u64);
-                    }
-                }
-                // pb.suspend(|| {
-                //     eprintln!("]");
-                // });
+                let mut stdout = io::stdout();
+                stdout.write_all(result).expect("write failed");
+                stdout.flush().expect("flush failed");
+            }
+            Some(Event::Metadata(metadata)) => pb.suspend(|| {
+                info!("Peer metadata: {}", String::from_utf8_lossy(metadata));
+            }),
+            Some(Event::Aborted) => {
+                pb.abandon();
+                error!("Transfer aborted by peer");
+                write_report(&session);
+                return Err("aborted by peer".into());
             }
-            Err(_e) => (),
+            _ => {}
         }
 
-        let current_time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_millis();
-        if current_time - start_timeout >= TIMEOUT {
-            start_timeout = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .expect("Time went backwards")
-                .as_millis();
-            if state == State::WaitingForResponse {
+        if event.is_some() || clock.now() - dashboard_drawn >= DASHBOARD_INTERVAL {
+            dashboard.set_message(session.packet_map().to_string());
+            dashboard_drawn = clock.now();
+        }
+
+        if event.is_some() || !outgoing.is_empty() {
+            let mut scheduler = scheduler.lock().unwrap();
+            for out in outgoing {
+                scheduler.queue_mut().submit(out);
+            }
+            pb.set_position(0);
+            pb.set_length(scheduler.queue().len() as u64);
+            if matches!(
+                event,
+                Some(Event::Evaluated { missing: 0 } | Event::Completed(_))
+            ) {
+                pb.finish();
+            }
+            if session.is_finished() {
                 pb.suspend(|| {
-                    info!("Timeout: resending Enquiry!");
+                    info!("Transfer complete in both directions");
                 });
-                received.clear();
-                let chunked = chunk_data(u16_to_u8_vec(broken_ids.clone()), CHUNK_SIZE);
-                let transmission = Transmission::new(make_transmission(chunked), true);
-                transmission_bins.extend(ready_for_send(transmission.clone().to_binary()));
-                pb.set_position(0);
-                pb.set_length(transmission_bins.len() as u64);
+                if !reported {
+                    pb.suspend(|| write_report(&session));
+                    reported = true;
+                }
+            }
+        }
+    }
+}
+
+/// Applies and removes the logging options, the rest is left for the (sub)command:
+/// `-v`/`-vv` for debug/trace, `-q`/`-qq` for warnings/errors only,
+/// `--log <file>` to also log to a file, `--log-format json` for JSON lines in it
+fn setup_logging(args: Vec<String>) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut verbosity = 0;
+    let mut file = None;
+    let mut format = log::Format::Plain;
+    let mut rest = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-v" | "-vv" | "-vvv" => verbosity += arg.len() as i32 - 1,
+            "-q" | "-qq" => verbosity -= arg.len() as i32 - 1,
+            "--log" => file = Some(args.next().ok_or("--log needs a file")?),
+            "--log-format" => {
+                format = match args.next().as_deref() {
+                    Some("plain") => log::Format::Plain,
+                    Some("json") => log::Format::JsonLines,
+                    _ => return Err("--log-format is plain or json".into()),
+                }
+            }
+            _ => rest.push(arg),
+        }
+    }
+    log::set_level(log::Level::Info.shifted(verbosity));
+    if let Some(path) = file {
+        let out = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?;
+        log::set_file(Box::new(out), format);
+    }
+    Ok(rest)
+}
+
+/// Value of `--name value`, an error if the value is missing
+fn option<'a>(args: &'a [String], name: &str) -> Result<Option<&'a String>, String> {
+    args.iter()
+        .position(|arg| arg == name)
+        .map(|at| args.get(at + 1).ok_or(format!("{name} needs a value")))
+        .transpose()
+}
+
+/// `16/2`: groups of 16 data packets, each followed by 2 parity packets
+fn parse_outer_code(value: &str) -> Result<OuterCode, String> {
+    value
+        .split_once('/')
+        .and_then(|(data, parity)| OuterCode::try_new(data.parse().ok()?, parity.parse().ok()?))
+        .ok_or(format!(
+            "--outer-code needs <data>/<parity> packets, at most 255 together, not {value}"
+        ))
+}
+
+/// Packets per interleaved block, 1 to 255
+fn parse_interleave_depth(value: &str) -> Result<u8, String> {
+    value.parse().ok().filter(|&depth| depth > 0).ok_or(format!(
+        "--interleave needs a depth of 1 to 255, not {value}"
+    ))
+}
+
+/// Data bytes per packet, at least 1
+fn parse_chunk_size(value: &str) -> Result<usize, String> {
+    value
+        .parse()
+        .ok()
+        .filter(|&size| size > 0)
+        .ok_or(format!("--chunk-size needs a number of bytes, not {value}"))
+}
+
+fn arq_config() -> ArqConfig {
+    ArqConfig {
+        initial_rto: TIMEOUT,
+        max_retries: MAX_RETRIES,
+        ..ArqConfig::default()
+    }
+}
+
+////////// decode //////////
+/// `v7 decode [--channels D0,D1,D2,CLK] <capture>`: breakdown of a nibble capture, either
+/// one nibble per byte, a capture recorded with `--record`, whose directions are shown one
+/// after the other, or a logic analyzer trace of the lines as VCD or sigrok CSV
+fn decode(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut channels = DEFAULT_CHANNELS;
+    let mut path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--channels" => {
+                let names: Vec<&str> = args
+                    .next()
+                    .map_or(Vec::new(), |names| names.split(',').collect());
+                channels = names
+                    .try_into()
+                    .map_err(|_| "--channels needs four names")?;
+            }
+            _ => path = Some(arg),
+        }
+    }
+    let Some(path) = path else {
+        error!("Usage: v7 decode [--channels D0,D1,D2,CLK] <capture>");
+        return Err("no capture given".into());
+    };
+    let bytes = std::fs::read(path)?;
+    if capture::is_capture(&bytes) {
+        let records = capture::read_capture(&bytes)?;
+        for direction in [Direction::Received, Direction::Sent] {
+            println!("{}", Yellow.paint(format!("{direction:?}")));
+            for item in dissect(&capture::nibbles(&records, direction)) {
+                println!("{item}");
+            }
+        }
+        return Ok(());
+    }
+    for item in dissect(&uncaptured_nibbles(bytes, &channels)?) {
+        println!("{item}");
+    }
+    Ok(())
+}
+
+/// Nibbles of a file that is no `--record` capture
+fn uncaptured_nibbles(bytes: Vec<u8>, channels: &[&str; 4]) -> io::Result<Vec<u8>> {
+    // a raw capture is nothing but nibbles, anything else is the text of a trace
+    if bytes.iter().all(|&byte| byte <= 0xF) {
+        return Ok(bytes);
+    }
+    let trace = read_trace(&String::from_utf8_lossy(&bytes), channels)?;
+    info!("{} clock edges in the trace", trace.samples.len());
+    Ok(trace.samples.iter().map(|&(_, nibble)| nibble).collect())
+}
+
+////////// replay //////////
+/// `v7 replay <capture>`: feeds what was received in a capture recorded with `--record`
+/// into a fresh session, the peer's data ends up on stdout like in a live transfer
+fn replay(path: Option<&String>) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(path) = path else {
+        error!("Usage: v7 replay <capture>");
+        return Err("no capture given".into());
+    };
+    let records = capture::read_capture(&std::fs::read(path)?)?;
+    // our own data is not in the capture, enquiries of the peer are answered with nothing
+    let transmission = Transmission::from_chunks(Vec::new(), FEC, OUTER_CODE)
+        .ok_or("too many packets for 16 bit IDs")?
+        .interleaved(INTERLEAVE_DEPTH);
+    let mut session = Session::new(transmission, CHUNK_SIZE, arq_config());
+    let replay = capture::replay(&records, &mut session, SystemClock.now());
+
+    for (at, event) in replay.events {
+        match event {
+            Event::Completed(data) => {
+                info!("{at:?}: completed, {} bytes", data.len());
+                let mut stdout = io::stdout();
+                stdout.write_all(&data)?;
+                stdout.flush()?;
+            }
+            event => info!("{at:?}: {event:?}"),
+        }
+    }
+    for (at, out) in replay.outgoing {
+        let out = match out {
+            Outgoing::Frame(_) => "frame",
+            Outgoing::Control(_) => "NAK",
+            Outgoing::Resend(_) => "resend",
+        };
+        info!("{at:?}: would send {out}");
+    }
+    Ok(())
+}
+
+////////// vcd //////////
+/// `v7 vcd [--annotate] <out.vcd> [capture]`: waveform of a capture recorded with `--record`,
+/// without one of the frame stdin would be sent as, timed by `CLK_DELAY`
+fn vcd(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let annotate = args.iter().any(|arg| arg == "--annotate");
+    let mut paths = args.iter().filter(|arg| !arg.starts_with("--"));
+    let Some(out) = paths.next() else {
+        error!("Usage: v7 vcd [--annotate] <out.vcd> [capture]");
+        return Err("no output file given".into());
+    };
+    let traces = match paths.next() {
+        Some(path) => {
+            let records = capture::read_capture(&std::fs::read(path)?)?;
+            vec![
+                Trace::from_records("sent", &records, Direction::Sent),
+                Trace::from_records("received", &records, Direction::Received),
+            ]
+        }
+        None => {
+            let chunked = chunk_data(read_stdin_as_vec_u8()?, CHUNK_SIZE);
+            let transmission = Transmission::from_chunks(chunked, FEC, OUTER_CODE)
+                .ok_or("CHUNK_SIZE is too long for a packet or too short for 16 bit IDs")?
+                .interleaved(INTERLEAVE_DEPTH);
+            let mut send_queue = SendQueue::with_preamble(PREAMBLE_PAIRS);
+            send_queue.push(Frame::from_transmission(transmission));
+            let nibbles: Vec<u8> = std::iter::from_fn(|| send_queue.pop()).collect();
+            vec![Trace::uniform("sent", &nibbles, CLK_DELAY)]
+        }
+    };
+    let mut file = io::BufWriter::new(std::fs::File::create(out)?);
+    write_vcd(&mut file, &traces, annotate)?;
+    file.flush()?;
+    info!("Wrote {out}");
+    Ok(())
+}
+
+////////// wave //////////
+/// `v7 wave [--sent] [--from <nibble>] [--width <nibbles>] <capture>`: the lines as a timing
+/// diagram, page by page. Captures recorded with `--record` show the received side unless
+/// `--sent` is given, raw captures and logic analyzer traces are read like `v7 decode` does.
+fn wave(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    const USAGE: &str = "Usage: v7 wave [--sent] [--from <nibble>] [--width <nibbles>] <capture>";
+    let mut direction = Direction::Received;
+    let mut from = 0;
+    let mut width = 40;
+    let mut path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--sent" => direction = Direction::Sent,
+            "--from" => from = args.next().ok_or(USAGE)?.parse()?,
+            "--width" => width = args.next().ok_or(USAGE)?.parse()?,
+            _ => path = Some(arg),
+        }
+    }
+    let Some(path) = path else {
+        error!("{USAGE}");
+        return Err("no capture given".into());
+    };
+    let bytes = std::fs::read(path)?;
+    let nibbles = if capture::is_capture(&bytes) {
+        capture::nibbles(&capture::read_capture(&bytes)?, direction)
+    } else {
+        uncaptured_nibbles(bytes, &DEFAULT_CHANNELS)?
+    };
+    let waveform = Waveform::new(&nibbles);
+
+    // enter pages on, `b` goes back, a number jumps to that nibble, `q` or EOF quits
+    let mut input = String::new();
+    loop {
+        from = from.min(waveform.len().saturating_sub(1));
+        print!("{}", waveform.render(from, width));
+        eprint!(
+            "{} ",
+            Yellow.paint(format!(
+                "[{from}..{} of {}] enter/b/<nibble>/q",
+                (from + width).min(waveform.len()),
+                waveform.len()
+            ))
+        );
+        input.clear();
+        if io::stdin().read_line(&mut input)? == 0 {
+            return Ok(());
+        }
+        match input.trim() {
+            "q" => return Ok(()),
+            "b" => from = from.saturating_sub(width),
+            "" => {
+                if from + width >= waveform.len() {
+                    return Ok(());
+                }
+                from += width;
+            }
+            jump => match jump.parse() {
+                Ok(nibble) => from = nibble,
+                Err(_) => error!("Unknown command {jump}"),
+            },
+        }
+    }
+}
+
+////////// table //////////
+/// `v7 table <report>...`: the speed table of README section 4.5 from `--report` files
+fn table(paths: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    if paths.is_empty() {
+        error!("Usage: v7 table <report>...");
+        return Err("no report given".into());
+    }
+    let mut reports = Vec::new();
+    for path in paths {
+        let report = Report::from_json(&std::fs::read_to_string(path)?)
+            .ok_or(format!("{path} is no transfer report"))?;
+        reports.push(report);
+    }
+    print!("{}", speed_table(&reports));
+    Ok(())
+}
+
+////////// selftest //////////
+/// `v7 selftest`, run on both sides at once: sends test rounds until one of the peer's
+/// arrived, then a few more so the peer gets one too, and reports every line
+/// Diagnoses the peer's latest round, one we started listening to halfway through is no use
+fn keep_round(diagnosis: &mut Option<Diagnosis>, round: Diagnosis) {
+    debug!("Round of {} edges from the peer", round.received_edges);
+    if round.received_edges >= round.expected_edges || diagnosis.is_none() {
+        *diagnosis = Some(round);
+    }
+}
+
+fn selftest() -> Result<(), Box<dyn std::error::Error>> {
+    // rounds sent after the peer's one arrived, and before giving up
+    const EXTRA_ROUNDS: usize = 2;
+    const MAX_ROUNDS: usize = 20;
+
+    let clock = SystemClock;
+    let mut link: Box<dyn Link> = Box::new(NanoLink(setup_nano()));
+    let nibbles = spawn_receiver(link.try_clone()?, RECEIVE_POLL);
+    let mut test = SelfTest::new();
+    let mut diagnosis = None;
+    let mut extra = 0;
+
+    info!("Waiting for the peer, start `v7 selftest` on the other side");
+    for _ in 0..MAX_ROUNDS {
+        // the peer's round may end while we send ours, or in our pause
+        for nibble in selftest::round_wire_nibbles() {
+            link.send(nibble)?;
+            std::thread::sleep(CLK_DELAY);
+            while let Ok(nibble) = nibbles.try_recv() {
+                test.on_nibble(nibble, clock.now());
+            }
+            if let Some(round) = test.poll(clock.now()) {
+                keep_round(&mut diagnosis, round);
+            }
+        }
+        // pause with the clock standing still
+        let pause_end = clock.now() + selftest::PAUSE;
+        while clock.now() < pause_end {
+            if let Ok(nibble) = nibbles.recv_timeout(CLK_DELAY) {
+                test.on_nibble(nibble, clock.now());
+            }
+            if let Some(round) = test.poll(clock.now()) {
+                keep_round(&mut diagnosis, round);
+            }
+        }
+        if diagnosis
+            .as_ref()
+            .is_some_and(|round| round.received_edges >= round.expected_edges)
+        {
+            extra += 1;
+            if extra > EXTRA_ROUNDS {
+                break;
             }
         }
     }
+
+    let Some(diagnosis) = diagnosis else {
+        error!("Nothing arrived: the peer is not running, or its clock line is not connected");
+        return Err("no clock from the peer".into());
+    };
+    println!("{diagnosis}");
+    if diagnosis.is_ok() {
+        info!("Wiring ok");
+        Ok(())
+    } else {
+        Err("wiring faults found".into())
+    }
 }
 
 ////////// nano functions //////////
 #[allow(dead_code)]
 fn setup_nano() -> Box<dyn SerialPort> {
     let mut port = serialport::new(PORT_NAME, BAUD_RATE)
-        .timeout(Duration::from_millis(
-            (CLK_DELAY - 3 * CLK_DELAY / 4) as u64,
-        ))
+        .timeout(PORT_TIMEOUT)
         .open()
         .unwrap();
 
@@ -222,7 +634,7 @@ fn setup_nano() -> Box<dyn SerialPort> {
 
     port.write_all(&[0xFF]).expect("port write panicked");
 
-    eprintln!("Serial port opened at {}", Yellow.paint(PORT_NAME));
+    info!("Serial port opened at {}", Yellow.paint(PORT_NAME));
     port
 }
 
@@ -238,6 +650,7 @@ fn send_nano(port: &mut Box<dyn SerialPort>, byte: u8) {
 fn receive_nano(port: &mut Box<dyn SerialPort>, buffer_size: usize) -> Result<u8, io::Error> {
     let mut buffer: Vec<u8> = vec![0; buffer_size];
     match port.read(&mut buffer) {
+        Ok(0) => Err(io::Error::new(io::ErrorKind::TimedOut, "Nothing read")),
         Ok(bytes_read) => {
             let received_data = &buffer[..bytes_read];
             Ok(received_data[0])
@@ -246,6 +659,23 @@ fn receive_nano(port: &mut Box<dyn SerialPort>, buffer_size: usize) -> Result<u8
     }
 }
 
+struct NanoLink(Box<dyn SerialPort>);
+
+impl Link for NanoLink {
+    fn send(&mut self, nibble: u8) -> io::Result<()> {
+        send_nano(&mut self.0, nibble);
+        Ok(())
+    }
+
+    fn receive(&mut self) -> io::Result<u8> {
+        receive_nano(&mut self.0, 1)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Link>> {
+        Ok(Box::new(NanoLink(self.0.try_clone()?)))
+    }
+}
+
 ////////// b15 functions //////////
 #[allow(dead_code)]
 fn setup_b15() -> B15F {
@@ -271,7 +701,10 @@ fn receive_b15(drv: &mut B15F, clock: &mut u8) -> Result<u8, io::Error> {
     let new_clock = received_data & 0b1000;
     // dbg!();
     if *clock == new_clock {
-        Err(io::Error::new(io::ErrorKind::Other, "Failed to read byte"))
+        Err(io::Error::new(
+            io::ErrorKind::WouldBlock,
+            "Failed to read byte",
+        ))
     } else {
         *clock = new_clock;
         // print_colored_byte(received_data);
@@ -279,129 +712,36 @@ fn receive_b15(drv: &mut B15F, clock: &mut u8) -> Result<u8, io::Error> {
     }
 }
 
-////////// other functions //////////
-#[allow(clippy::too_many_lines)]
-fn auswertung(
-    data: Vec<u8>,
-    start: usize,
-    end: usize,
-    transmission_packet_array: &mut Vec<Vec<u8>>,
-    init_transmission: &Transmission,
-    transmission_bins: &mut Vec<u8>,
-    state: &mut State,
-) -> Vec<u16> {
-    let sliced_data = slice_data(data, start, end);
-    let mut squashed_data: Vec<u8> = Vec::new();
-    for x in sliced_data.chunks(2) {
-        let mut res: u8 = 0b0;
-        res |= x[0] << 4;
-        let second_nibble = x.get(1);
-        if let Some(nibble) = second_nibble {
-            res |= nibble;
-        }
-        squashed_data.push(res);
-    }
-    let mut p = ProtocolDecoder::new(squashed_data);
-    let transmission = p.decode();
-    if transmission.header.is_enquiry {
-        let mut ids: Vec<u16> = Vec::new();
-        for mut packet in transmission.packets {
-            let decoder = Decoder::new(packet.header.ecc_size as usize);
-            let header_vec = packet.header.to_vec();
-            let mut msg = header_vec.clone();
-            msg.append(&mut packet.data.clone());
-            msg.append(&mut packet.ecc.clone());
-            if msg.len() > 128 {
-                error!("Packet too long: {}", msg.len());
-                continue;
-            }
-            let decoded = decoder.correct_err_count(&msg, None);
-            match decoded {
-                Ok(content) => {
-                    let buffer = content.0;
-                    let errors = content.1;
-                    if errors > 0 {
-                        info!(
-                            "Repaired Packet {}, had {} errors!",
-                            packet.header.id, errors
-                        );
-                    }
-                    buffer.data().to_vec()[header_vec.len()..].clone_into(&mut packet.data);
-                    packet.ecc = buffer.ecc().to_vec();
-                    let local_ids: Vec<u16> = packet
-                        .data
-                        .chunks(2)
-                        .map(|x| u16::from_le_bytes([x[0], x[1]]))
-                        .collect();
-                    ids.extend(local_ids);
-                }
-                Err(e) => {
-                    let id = packet.header.id;
-                    info!("Packet {id} unrecoverable: {e:?}\n{packet:?}");
-                }
-            }
-        }
-        // respond with data for requested packets
-        let mut transmission_now = init_transmission.clone();
-        for i in (1..=transmission_now.packets.len()).rev() {
-            assert!(i < u16::MAX as usize, "ID too large! (What did you do?)");
-            if !ids.contains(&(i as u16)) {
-                transmission_now.packets.retain(|x| x.header.id != i as u16);
-            }
-        }
-        transmission_bins.extend(ready_for_send(transmission_now.clone().to_binary()));
-        info!("Responding to Enquiry...");
-    } else {
-        *state = State::Normal;
-        for mut packet in transmission.packets {
-            let decoder = Decoder::new(packet.header.ecc_size as usize);
-            let header_vec = packet.header.to_vec();
-            let mut msg = header_vec.clone();
-            msg.append(&mut packet.data.clone());
-            msg.append(&mut packet.ecc.clone());
-            if msg.len() > 128 {
-                error!("Packet too long: {}", msg.len());
-                continue;
-            }
-            let decoded = decoder.correct_err_count(&msg, None);
-            match decoded {
-                Ok((buffer, errors)) => {
-                    if errors > 0 {
-                        info!("Packet {} had {} errors!", packet.header.id, errors);
-                    }
-                    buffer.data().to_vec()[header_vec.len()..].clone_into(&mut packet.data);
-                    packet.ecc = buffer.ecc().to_vec();
-                    info!(
-                        "{} ({}/{})",
-                        Yellow.paint("Packet OK"),
-                        packet.header.id,
-                        transmission.header.total_packets
-                    );
-                    transmission_packet_array[packet.header.id as usize] = packet.data;
-                }
-                Err(e) => {
-                    let id = packet.header.id;
-                    info!("Packet {id} unrecoverable: {e:?}\n{packet:?}");
-                }
-            }
+#[allow(dead_code)]
+struct B15Link {
+    drv: Arc<Mutex<B15F>>,
+    clock: u8,
+}
+
+#[allow(dead_code)]
+impl B15Link {
+    fn new(drv: B15F) -> Self {
+        Self {
+            drv: Arc::new(Mutex::new(drv)),
+            clock: 0,
         }
     }
-    let mut unrepairable_packets: Vec<u16> = Vec::new();
-    let total_packets = transmission.header.total_packets;
-    for packet_id in 1..=total_packets {
-        // 1 - da es kein packet mit id 0 gibt
-        if transmission_packet_array[packet_id as usize] == Vec::new() {
-            unrepairable_packets.push(packet_id);
-        }
+}
+
+impl Link for B15Link {
+    fn send(&mut self, nibble: u8) -> io::Result<()> {
+        send_b15(&mut self.drv.lock().unwrap(), nibble);
+        Ok(())
     }
 
-    if unrepairable_packets.is_empty() && !transmission.header.is_enquiry {
-        let result: Vec<u8> = transmission_packet_array.concat();
-        let mut stdout = io::stdout();
-        info!("Writing data to stdout...");
-        stdout.write_all(&result).expect("write failed");
-        stdout.flush().expect("flush failed");
+    fn receive(&mut self) -> io::Result<u8> {
+        receive_b15(&mut self.drv.lock().unwrap(), &mut self.clock)
     }
 
-    unrepairable_packets
+    fn try_clone(&self) -> io::Result<Box<dyn Link>> {
+        Ok(Box::new(B15Link {
+            drv: Arc::clone(&self.drv),
+            clock: self.clock,
+        }))
+    }
 }
@@ -0,0 +1,544 @@
+//! Follows the peer's nibbles symbol by symbol while they arrive, so losses are noticed
+//! mid-transmission instead of after EOT.
+//!
+//! Packet IDs of a data transmission are sequential, a skipped ID or a packet beyond repair
+//! is reported as a gap right away. With an outer code only groups that lost more packets
+//! than parity can rebuild are reported, once the next group starts.
+//! NAKs of the peer are picked up the same way.
+//! Interleaved packets are judged once their block is complete.
+//!
+//! A symbol that can't have been sent (an unknown control code, or a control code that is
+//! never sent) means a nibble was lost or gained. The monitor then hunts for the start of a
+//! frame, a NAK or, within a frame, the next packet header again, nibble by nibble, and stays
+//! in the frame until the next one starts. A packet header failing its checksum only skips
+//! its packet.
+
+use std::mem;
+
+use crate::{
+    controls::{Control, Symbol, UnknownControl},
+    debug,
+    encoder::unclock_symbol,
+    fec::FecScheme,
+    interleave::{Interleaver, FILLER},
+    outer::OuterCode,
+    protocol::{Message, Nak, Packet, PacketHeader, TransmissionHeader, NAK_MAX_IDS, VERSION},
+};
+
+/// Segments growing beyond this mean the EOT was missed,
+/// the longest packet has a `u16` worth of data and of ECC
+const MAX_SEGMENT: usize = 2 * u16::MAX as usize + 16;
+
+/// Noticed while a transmission is still arriving
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Notice {
+    /// Packets of the peer's data that were skipped or arrived broken
+    Gap(Vec<u16>),
+    /// The peer asks us to resend these packets
+    Nak(Vec<u16>),
+}
+
+pub struct StreamMonitor {
+    /// Hunting: the last nibbles seen, synced: the nibbles of the current symbol
+    nibbles: Vec<u8>,
+    synced: bool,
+    /// Inside SOT..EOT, a NAK outside of it is a frame of its own
+    in_frame: bool,
+    /// Nibbles since the SOT of a new frame, right after the nibble that revealed it
+    frame_started: Option<usize>,
+    /// An SOT came before the EOT of the frame, it starts a new frame if its header is valid
+    sot_in_frame: bool,
+    /// The last nibble completed the EOT of a frame followed symbol by symbol
+    frame_ended: bool,
+    segment: Vec<Symbol>,
+    /// Total packets of the data transmission being received, `None` for anything else
+    total_packets: Option<u16>,
+    outer_code: Option<OuterCode>,
+    fec: FecScheme,
+    /// Format of the packet headers
+    version: u8,
+    /// Packets arrive in interleaved blocks, collected in `block`
+    interleaver: Option<Interleaver>,
+    block: Vec<Symbol>,
+    next_id: u16,
+    /// Outer code: group being received, its data packets seen and parity packets seen
+    group: u16,
+    group_data: Vec<u16>,
+    group_parity: usize,
+}
+
+impl StreamMonitor {
+    pub fn new() -> Self {
+        Self {
+            nibbles: Vec::with_capacity(3),
+            synced: false,
+            in_frame: false,
+            frame_started: None,
+            sot_in_frame: false,
+            frame_ended: false,
+            segment: Vec::new(),
+            total_packets: None,
+            outer_code: None,
+            fec: FecScheme::default(),
+            version: VERSION,
+            interleaver: None,
+            block: Vec::new(),
+            next_id: 1,
+            group: 0,
+            group_data: Vec::new(),
+            group_parity: 0,
+        }
+    }
+
+    /// Between the SOT of a frame and its EOT
+    pub fn in_frame(&self) -> bool {
+        self.in_frame
+    }
+
+    /// Following a frame symbol by symbol: an EOT at the end of what was received
+    /// before [`StreamMonitor::frame_ended`] is data that looks like one
+    pub fn is_following(&self) -> bool {
+        self.synced && self.in_frame
+    }
+
+    /// The nibble just pushed completed the EOT of a frame followed symbol by symbol
+    pub fn frame_ended(&self) -> bool {
+        self.frame_ended
+    }
+
+    /// `Some` right after the nibble that revealed a new frame: the number of nibbles pushed
+    /// since the first one of its SOT. That's the SOT itself between frames, within a frame
+    /// whose EOT went missing it's the end of the transmission header.
+    pub fn frame_started(&self) -> Option<usize> {
+        self.frame_started
+    }
+
+    /// Feeds one received nibble
+    pub fn push(&mut self, nibble: u8) -> Option<Notice> {
+        self.frame_started = None;
+        self.frame_ended = false;
+        self.nibbles.push(nibble);
+        if self.nibbles.len() < 3 {
+            return None;
+        }
+        let symbol = unclock_symbol(&self.nibbles);
+        if self.synced {
+            match symbol {
+                Ok(symbol) if is_sent(symbol) => {
+                    self.nibbles.clear();
+                    return self.on_symbol(symbol);
+                }
+                Ok(_) | Err(UnknownControl(_)) => {
+                    self.nibbles.remove(0);
+                    self.resync();
+                    return None;
+                }
+            }
+        }
+        // hunt for a frame start, SOT or a standalone NAK, within a frame for the next packet.
+        // Misaligned data looks like any of them now and then: a packet header has to pass its
+        // check and the symbols that follow have to be valid. An EOT would end the frame for
+        // good, it isn't looked for.
+        match symbol {
+            Ok(symbol @ Symbol::Control(Control::Sot | Control::Nac)) => {
+                self.start_segment(symbol);
+            }
+            Ok(symbol @ Symbol::Control(Control::Soh))
+                if self.in_frame && self.interleaver.is_none() =>
+            {
+                self.segment = vec![symbol];
+            }
+            _ => {
+                self.nibbles.remove(0);
+                return None;
+            }
+        }
+        self.nibbles.clear();
+        self.synced = true;
+        None
+    }
+
+    /// A NAK outside of a frame is a frame of its own
+    fn start_segment(&mut self, symbol: Symbol) {
+        if symbol == Symbol::Control(Control::Sot) {
+            if self.in_frame {
+                self.sot_in_frame = true;
+            } else {
+                self.frame_started = Some(3);
+                self.in_frame = true;
+            }
+        }
+        self.segment = vec![symbol];
+    }
+
+    /// Symbols were lost or gained: the segment is dropped, hunting goes on
+    fn resync(&mut self) {
+        debug!("Lost the symbol boundaries, hunting for the next segment");
+        self.synced = false;
+        self.sot_in_frame = false;
+        self.segment.clear();
+        self.block.clear();
+    }
+
+    fn on_symbol(&mut self, symbol: Symbol) -> Option<Notice> {
+        // a NAK ends after its length, not at the next control symbol
+        if self.segment.first() == Some(&Symbol::Control(Control::Nac)) {
+            self.segment.push(symbol);
+            let count = self.segment.get(1)?.byte() as usize;
+            if count <= NAK_MAX_IDS && self.segment.len() < Nak::symbol_count(count) {
+                return None;
+            }
+            let segment = mem::take(&mut self.segment);
+            if !self.in_frame {
+                self.synced = false;
+            }
+            let bytes: Vec<u8> = segment[1..].iter().map(|symbol| symbol.byte()).collect();
+            return Nak::from_bytes(&bytes).map(|nak| Notice::Nak(nak.ids));
+        }
+        if let Some(interleaver) = self.interleaver {
+            return self.on_block_symbol(interleaver, symbol);
+        }
+
+        let boundary = match symbol {
+            Symbol::Control(
+                control @ (Control::Sot | Control::Soh | Control::Nac | Control::Eot),
+            ) => control,
+            _ => {
+                self.segment.push(symbol);
+                if self.segment.len() > MAX_SEGMENT {
+                    self.lose_sync();
+                } else if !self.header_intact() {
+                    // the rest of the packet is skipped up to the next boundary
+                    self.segment.clear();
+                }
+                return None;
+            }
+        };
+
+        let notice = self.finish_segment();
+        if boundary == Control::Eot {
+            self.frame_ended = true;
+            self.lose_sync();
+        } else if let Some(interleaver) = self.interleaver {
+            // the header is done, this is the first symbol of a block
+            return notice.or(self.on_block_symbol(interleaver, symbol));
+        } else {
+            self.start_segment(symbol);
+        }
+        notice
+    }
+
+    /// `false` once a packet header is complete and fails its checksum.
+    /// Without a checksum (version 1) only a missing SOTX behind it tells.
+    fn header_intact(&self) -> bool {
+        let header_len = PacketHeader::chunk_len(self.version);
+        if self.segment.first() != Some(&Symbol::Control(Control::Soh))
+            || self.segment.len() != header_len + 1
+        {
+            return true;
+        }
+        let bytes: Vec<u8> = self.segment[..header_len]
+            .iter()
+            .map(|symbol| symbol.byte())
+            .collect();
+        self.segment[header_len] == Symbol::Control(Control::Sotx)
+            && PacketHeader::parse(&bytes, self.version).is_some()
+    }
+
+    fn lose_sync(&mut self) {
+        self.synced = false;
+        self.in_frame = false;
+        self.sot_in_frame = false;
+        self.segment.clear();
+        self.total_packets = None;
+        self.interleaver = None;
+        self.block.clear();
+    }
+
+    /// NAKs and EOT only come between blocks, the packets of a full block are judged in order
+    fn on_block_symbol(&mut self, interleaver: Interleaver, symbol: Symbol) -> Option<Notice> {
+        if self.block.is_empty() {
+            match symbol {
+                Symbol::Control(Control::Nac) => {
+                    self.segment = vec![symbol];
+                    return None;
+                }
+                Symbol::Control(Control::Eot) => {
+                    self.frame_ended = true;
+                    self.lose_sync();
+                    return None;
+                }
+                _ => {}
+            }
+        }
+        self.block.push(symbol);
+        if self.block.len() < interleaver.block_len() {
+            return None;
+        }
+        let block = mem::take(&mut self.block);
+        let mut gap = Vec::new();
+        for row in interleaver
+            .deinterleave(&block, FILLER)
+            .chunks(interleaver.row_len as usize)
+        {
+            // rows of filler complete the last block
+            if row.first() != Some(&Symbol::Control(Control::Soh)) {
+                continue;
+            }
+            if let Some(Notice::Gap(ids)) = self.on_packet(row) {
+                gap.extend(ids);
+            }
+        }
+        (!gap.is_empty()).then_some(Notice::Gap(gap))
+    }
+
+    fn finish_segment(&mut self) -> Option<Notice> {
+        let segment = mem::take(&mut self.segment);
+        let bytes: Vec<u8> = segment.iter().map(|symbol| symbol.byte()).collect();
+        match segment.first() {
+            Some(Symbol::Control(Control::Sot)) => {
+                let header = TransmissionHeader::from_bytes(&bytes, &[]);
+                if mem::take(&mut self.sot_in_frame) && header.is_some() {
+                    // the boundary symbol after the header was pushed as well
+                    self.frame_started = Some(3 * (segment.len() + 1));
+                }
+                // packet headers of every message are checked, only data is followed by ID
+                self.version = header.as_ref().map_or(VERSION, |header| header.version);
+                let header = header.filter(|h| h.message == Message::Data);
+                self.total_packets = header.as_ref().map(|header| header.total_packets);
+                self.outer_code = header.as_ref().and_then(|header| header.outer_code);
+                self.fec = header
+                    .as_ref()
+                    .map_or(FecScheme::default(), |header| header.fec);
+                self.interleaver = header.and_then(|header| header.interleaver);
+                self.block.clear();
+                self.next_id = 1;
+                self.group = 0;
+                self.group_data.clear();
+                self.group_parity = 0;
+                None
+            }
+            Some(Symbol::Control(Control::Soh)) => self.on_packet(&segment),
+            _ => None,
+        }
+    }
+
+    fn on_packet(&mut self, segment: &[Symbol]) -> Option<Notice> {
+        let total_packets = self.total_packets?;
+        let packet = Packet::from_stream(segment, &[], self.version)
+            .into_iter()
+            .next()
+            .and_then(|packet| packet.repair(&*self.fec.codec()))
+            .map(|(packet, _)| packet.header.id);
+        if let Some(outer_code) = self.outer_code {
+            return self.on_grouped_packet(outer_code, total_packets, packet);
+        }
+
+        match packet {
+            Some(id) if (1..=total_packets).contains(&id) => {
+                let gap: Vec<u16> = (self.next_id..id).collect();
+                self.next_id = self.next_id.max(id.saturating_add(1));
+                (!gap.is_empty()).then_some(Notice::Gap(gap))
+            }
+            // the ID can't be trusted, blame the one expected next
+            _ if self.next_id <= total_packets => {
+                let id = self.next_id;
+                self.next_id += 1;
+                Some(Notice::Gap(vec![id]))
+            }
+            _ => None,
+        }
+    }
+
+    /// Packets beyond repair are left to the parity, the group is judged once the next one starts
+    fn on_grouped_packet(
+        &mut self,
+        outer_code: OuterCode,
+        total_packets: u16,
+        id: Option<u16>,
+    ) -> Option<Notice> {
+        let id = id.filter(|&id| {
+            id > 0 && outer_code.group_of(total_packets, id) < outer_code.groups(total_packets)
+        })?;
+        let group = outer_code.group_of(total_packets, id);
+        if group < self.group {
+            // resent packet of an earlier group
+            return None;
+        }
+
+        let mut gap = Vec::new();
+        if group > self.group {
+            // more missing data packets than parity arrived: the group can't be rebuilt
+            let missing: Vec<u16> = outer_code
+                .data_ids(total_packets, self.group)
+                .filter(|id| !self.group_data.contains(id))
+                .collect();
+            if missing.len() > self.group_parity {
+                gap = missing;
+            }
+            for skipped in self.group + 1..group {
+                gap.extend(outer_code.data_ids(total_packets, skipped));
+            }
+            self.group = group;
+            self.group_data.clear();
+            self.group_parity = 0;
+        }
+        if id <= total_packets {
+            self.group_data.push(id);
+        } else {
+            self.group_parity += 1;
+        }
+        (!gap.is_empty()).then_some(Notice::Gap(gap))
+    }
+}
+
+/// Control codes that are never sent can only come from symbols put together wrongly
+fn is_sent(symbol: Symbol) -> bool {
+    !matches!(
+        symbol,
+        Symbol::Control(Control::Soth | Control::Eotx | Control::Enq | Control::Ack)
+    )
+}
+
+impl Default for StreamMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encoder::clock_symbol, protocol::Transmission};
+
+    /// Segments of a data transmission of `count` packets with 32 bytes each
+    fn segments(count: usize) -> Vec<Vec<Symbol>> {
+        let chunks = (0..count)
+            .map(|packet| (0..32).map(|i| (packet * 32 + i * 7) as u8).collect())
+            .collect();
+        Transmission::from_chunks(chunks, FecScheme::default(), None)
+            .unwrap()
+            .into_segments()
+            .collect()
+    }
+
+    /// Clocked nibbles of the symbols as the link reports them
+    fn clocked(symbols: impl IntoIterator<Item = Symbol>) -> Vec<u8> {
+        let mut clock = 0;
+        let mut nibbles = Vec::new();
+        for symbol in symbols {
+            nibbles.extend(clock_symbol(symbol, clock));
+            clock ^= 1;
+        }
+        nibbles
+    }
+
+    /// Notices while pushing `nibbles`
+    fn push_all(monitor: &mut StreamMonitor, nibbles: &[u8]) -> Vec<Notice> {
+        nibbles
+            .iter()
+            .filter_map(|&nibble| monitor.push(nibble))
+            .collect()
+    }
+
+    #[test]
+    fn complete_frame_has_no_gaps() {
+        let mut monitor = StreamMonitor::new();
+        let nibbles = clocked(segments(4).concat());
+        let mut started = Vec::new();
+        let mut notices = Vec::new();
+        for (i, &nibble) in nibbles.iter().enumerate() {
+            notices.extend(monitor.push(nibble));
+            if let Some(back) = monitor.frame_started() {
+                started.push(i + 1 - back);
+            }
+            // the frame ends with the last nibble of EOT
+            assert_eq!(monitor.in_frame(), i >= 2 && i < nibbles.len() - 1, "{i}");
+        }
+        assert_eq!(started, [0]);
+        assert!(notices.is_empty(), "{notices:?}");
+    }
+
+    #[test]
+    fn skipped_packet_is_a_gap() {
+        let mut monitor = StreamMonitor::new();
+        let mut segments = segments(4);
+        // header, packets 1 to 4, EOT
+        segments.remove(2);
+        let notices = push_all(&mut monitor, &clocked(segments.concat()));
+        assert_eq!(notices, [Notice::Gap(vec![2])]);
+    }
+
+    #[test]
+    fn nak_outside_a_frame_is_noticed() {
+        let mut monitor = StreamMonitor::new();
+        let mut nibbles = vec![0b1000, 0, 0b1000];
+        nibbles.extend(clocked(Nak::new(vec![3, 300]).to_binary()));
+        let notices = push_all(&mut monitor, &nibbles);
+        assert_eq!(notices, [Notice::Nak(vec![3, 300])]);
+        assert!(!monitor.in_frame());
+    }
+
+    #[test]
+    fn lost_nibble_hunts_for_the_next_packet() {
+        let segments = segments(4);
+        let before: usize = segments[..2].iter().map(Vec::len).sum();
+        for lost in 1..=5 {
+            let mut monitor = StreamMonitor::new();
+            let mut nibbles = clocked(segments.concat());
+            // a few symbols into the data of packet 2
+            let at = (before + 20) * 3;
+            nibbles.drain(at..at + lost);
+            let mut notices = Vec::new();
+            for (i, &nibble) in nibbles.iter().enumerate() {
+                notices.extend(monitor.push(nibble));
+                assert_eq!(
+                    monitor.in_frame(),
+                    i >= 2 && i < nibbles.len() - 1,
+                    "{lost}: {i}"
+                );
+            }
+            // packets 3 and 4 are still followed
+            assert_eq!(notices, [Notice::Gap(vec![2])], "{lost}");
+        }
+    }
+
+    #[test]
+    fn broken_packet_header_hunts_for_the_next_packet() {
+        let mut segments = segments(4);
+        // the length of packet 2 is off, its data would run into packet 3
+        segments[2][3] = Symbol::Data(segments[2][3].byte() ^ 0x10);
+        let mut monitor = StreamMonitor::new();
+        let notices = push_all(&mut monitor, &clocked(segments.concat()));
+        assert_eq!(notices, [Notice::Gap(vec![2])]);
+        assert!(!monitor.in_frame());
+    }
+
+    #[test]
+    fn frame_after_a_lost_eot_starts_at_its_sot() {
+        let first = segments(2);
+        let mut nibbles = clocked(first.concat());
+        // a few symbols into the data of the last packet, the EOT is never found
+        let at = (first[0].len() + first[1].len() + 20) * 3;
+        nibbles.remove(at);
+        let mut monitor = StreamMonitor::new();
+        assert!(push_all(&mut monitor, &nibbles).is_empty());
+        assert!(monitor.in_frame());
+
+        let second = segments(2);
+        // misaligned by a stray nibble
+        nibbles.push(0);
+        let sot = nibbles.len();
+        nibbles.extend(clocked(second.concat()));
+        let mut monitor = StreamMonitor::new();
+        let mut started = Vec::new();
+        for (i, &nibble) in nibbles.iter().enumerate() {
+            monitor.push(nibble);
+            if let Some(back) = monitor.frame_started() {
+                started.push(i + 1 - back);
+            }
+        }
+        assert_eq!(started, [0, sot]);
+        assert!(!monitor.in_frame());
+    }
+}
@@ -0,0 +1,278 @@
+//! Erasure code across packets: every group of `data` packets is followed by `parity` packets,
+//! so up to `parity` lost packets per group are rebuilt without asking the peer again.
+//!
+//! Byte `j` of the parity packets is the Reed-Solomon ECC over byte `j` of every data packet
+//! in the group. Data packets enter it as `[length (u16 BE), data.., zero padding]`,
+//! so a shorter last packet is rebuilt with its length.
+
+use std::ops::RangeInclusive;
+
+use reed_solomon::{Decoder, Encoder};
+
+use crate::{fec::Fec, protocol::Packet, utilities::split_u16};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OuterCode {
+    /// Data packets per group
+    pub data: u8,
+    /// Parity packets per group, as many lost packets can be rebuilt
+    pub parity: u8,
+}
+
+impl OuterCode {
+    pub const fn new(data: u8, parity: u8) -> Self {
+        assert!(
+            data > 0 && parity > 0,
+            "groups need data and parity packets"
+        );
+        assert!(
+            data as usize + parity as usize <= 255,
+            "a group must fit one Reed-Solomon codeword"
+        );
+        Self { data, parity }
+    }
+
+    /// `None` for a combination `new` would reject, e.g. from a damaged header
+    pub fn try_new(data: u8, parity: u8) -> Option<Self> {
+        (data > 0 && parity > 0 && data as usize + parity as usize <= 255)
+            .then_some(Self { data, parity })
+    }
+
+    pub fn groups(&self, total_packets: u16) -> u16 {
+        total_packets.div_ceil(self.data as u16)
+    }
+
+    /// IDs of the data packets in `group`
+    pub fn data_ids(&self, total_packets: u16, group: u16) -> RangeInclusive<u16> {
+        let first = group * self.data as u16 + 1;
+        first..=(first + self.data as u16 - 1).min(total_packets)
+    }
+
+    /// IDs of the parity packets of `group`, numbered on from the last data packet
+    pub fn parity_ids(&self, total_packets: u16, group: u16) -> RangeInclusive<u16> {
+        let first = total_packets + group * self.parity as u16 + 1;
+        first..=first + self.parity as u16 - 1
+    }
+
+    /// Group a data or parity packet belongs to
+    pub fn group_of(&self, total_packets: u16, id: u16) -> u16 {
+        if id <= total_packets {
+            (id - 1) / self.data as u16
+        } else {
+            (id - total_packets - 1) / self.parity as u16
+        }
+    }
+
+    /// Puts the parity packets behind every group of `packets`, which have the IDs `1..=len`.
+    /// Parity packets get their ECC from `fec` like the data packets,
+    /// `None` if they are too long for it.
+    pub fn encode(&self, packets: Vec<Packet>, fec: &dyn Fec) -> Option<Vec<Packet>> {
+        let total_packets = packets.len() as u16;
+        let groups = self.groups(total_packets);
+        assert!(
+            (total_packets as usize + groups as usize * self.parity as usize) < u16::MAX as usize,
+            "too many packets for the outer code"
+        );
+
+        let mut encoded =
+            Vec::with_capacity(packets.len() + groups as usize * self.parity as usize);
+        let mut packets = packets.into_iter();
+        for group in 0..groups {
+            let members: Vec<Packet> = packets.by_ref().take(self.data as usize).collect();
+            let rows: Vec<Vec<u8>> = members.iter().map(|packet| packet.data.clone()).collect();
+            encoded.extend(members);
+            for (id, data) in self
+                .parity_ids(total_packets, group)
+                .zip(self.parity_rows(&rows))
+            {
+                encoded.push(Packet::with_fec(data, id, fec)?);
+            }
+        }
+        Some(encoded)
+    }
+
+    /// Rebuilds the missing data packets of every group that lost at most `parity` packets.
+    /// `packets` is indexed by ID, an empty entry is missing. Returns the IDs rebuilt.
+    pub fn rebuild(&self, total_packets: u16, packets: &mut [Vec<u8>]) -> Vec<u16> {
+        let is_missing =
+            |packets: &[Vec<u8>], id: u16| packets.get(id as usize).is_none_or(Vec::is_empty);
+        let mut rebuilt = Vec::new();
+
+        for group in 0..self.groups(total_packets) {
+            let ids: Vec<u16> = self
+                .data_ids(total_packets, group)
+                .chain(self.parity_ids(total_packets, group))
+                .collect();
+            let data_count = ids.len() - self.parity as usize;
+            let missing: Vec<u8> = (0..ids.len())
+                .filter(|&at| is_missing(packets, ids[at]))
+                .map(|at| at as u8)
+                .collect();
+            if missing.len() > self.parity as usize
+                || !missing.iter().any(|&at| (at as usize) < data_count)
+            {
+                continue;
+            }
+            let Some(width) = ids[data_count..]
+                .iter()
+                .find(|&&id| !is_missing(packets, id))
+                .map(|&id| packets[id as usize].len())
+            else {
+                continue;
+            };
+
+            let mut rows: Vec<Vec<u8>> = ids
+                .iter()
+                .enumerate()
+                .map(|(at, &id)| {
+                    let bytes = packets.get(id as usize).map_or(&[][..], Vec::as_slice);
+                    let mut row = if at < data_count && !bytes.is_empty() {
+                        length_prefixed(bytes)
+                    } else {
+                        bytes.to_vec()
+                    };
+                    row.resize(width, 0);
+                    row
+                })
+                .collect();
+
+            let decoder = Decoder::new(self.parity as usize);
+            let repaired = (0..width).all(|column| {
+                let codeword: Vec<u8> = rows.iter().map(|row| row[column]).collect();
+                let Ok(corrected) = decoder.correct(&codeword, Some(&missing)) else {
+                    return false;
+                };
+                for &at in &missing {
+                    rows[at as usize][column] = corrected[at as usize];
+                }
+                true
+            });
+            if !repaired {
+                continue;
+            }
+
+            for &at in missing.iter().filter(|&&at| (at as usize) < data_count) {
+                let row = &rows[at as usize];
+                let len = (row[0] as usize) << 8 | row[1] as usize;
+                let Some(data) = row.get(2..2 + len) else {
+                    continue;
+                };
+                packets[ids[at as usize] as usize] = data.to_vec();
+                rebuilt.push(ids[at as usize]);
+            }
+        }
+        rebuilt
+    }
+
+    /// Column-wise ECC over the length-prefixed `rows`, one row per parity packet
+    fn parity_rows(&self, rows: &[Vec<u8>]) -> Vec<Vec<u8>> {
+        let rows: Vec<Vec<u8>> = rows.iter().map(|row| length_prefixed(row)).collect();
+        let width = rows.iter().map(Vec::len).max().unwrap_or(1);
+        let encoder = Encoder::new(self.parity as usize);
+        let mut parity = vec![vec![0u8; width]; self.parity as usize];
+        for column in 0..width {
+            let codeword: Vec<u8> = rows
+                .iter()
+                .map(|row| row.get(column).copied().unwrap_or(0))
+                .collect();
+            for (row, &byte) in parity.iter_mut().zip(encoder.encode(&codeword).ecc()) {
+                row[column] = byte;
+            }
+        }
+        parity
+    }
+}
+
+fn length_prefixed(data: &[u8]) -> Vec<u8> {
+    assert!(
+        data.len() <= u16::MAX as usize,
+        "packet too long for the outer code"
+    );
+    let mut row = Vec::with_capacity(data.len() + 2);
+    row.extend(split_u16(data.len() as u16));
+    row.extend(data);
+    row
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fec::FecScheme, protocol::Transmission};
+
+    const CODE: OuterCode = OuterCode::new(4, 2);
+
+    /// 10 data packets of uneven sizes, the last group has only 2 of them
+    fn chunks() -> Vec<Vec<u8>> {
+        (1..=10u8)
+            .map(|id| (0..id as usize * 7 % 23).map(|i| id ^ i as u8).collect())
+            .collect()
+    }
+
+    /// Data of the encoded packets indexed by ID
+    fn received() -> Vec<Vec<u8>> {
+        let transmission =
+            Transmission::from_chunks(chunks(), FecScheme::default(), Some(CODE)).unwrap();
+        assert_eq!(transmission.header.total_packets, 10);
+        let mut packets = vec![Vec::new(); transmission.packets.len() + 1];
+        for packet in transmission.packets {
+            packets[packet.header.id as usize] = packet.data;
+        }
+        packets
+    }
+
+    #[test]
+    fn groups_of_the_packets() {
+        assert_eq!(CODE.groups(10), 3);
+        assert_eq!(CODE.data_ids(10, 2), 9..=10);
+        assert_eq!(CODE.parity_ids(10, 2), 15..=16);
+        assert_eq!(CODE.group_of(10, 10), 2);
+        assert_eq!(CODE.group_of(10, 16), 2);
+    }
+
+    #[test]
+    fn up_to_parity_lost_packets_per_group_are_rebuilt() {
+        let chunks = chunks();
+        for group in 0..CODE.groups(10) {
+            let members: Vec<u16> = CODE
+                .data_ids(10, group)
+                .chain(CODE.parity_ids(10, group))
+                .collect();
+            for (i, &first) in members.iter().enumerate() {
+                for &second in &members[i..] {
+                    let mut packets = received();
+                    packets[first as usize].clear();
+                    packets[second as usize].clear();
+
+                    let mut lost: Vec<u16> = vec![first, second];
+                    lost.retain(|&id| id <= 10);
+                    lost.dedup();
+                    assert_eq!(CODE.rebuild(10, &mut packets), lost, "{first} {second}");
+                    assert_eq!(packets[1..=10], chunks[..], "{first} {second}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn group_with_too_many_lost_packets_stays_incomplete() {
+        let mut packets = received();
+        for id in [1, 3, 4, 10] {
+            packets[id].clear();
+        }
+        assert_eq!(CODE.rebuild(10, &mut packets), [10]);
+        assert!(packets[1].is_empty() && packets[3].is_empty() && packets[4].is_empty());
+    }
+
+    #[test]
+    fn length_prefix_round_trips() {
+        for len in [0, 1, 2, 255, 256, 257, 1000] {
+            let data: Vec<u8> = (0..len).map(|i| (i * 31) as u8).collect();
+            let mut row = length_prefixed(&data);
+            // the rebuilt row comes back padded to the longest packet of the group
+            row.resize(1200, 0);
+            let read = (row[0] as usize) << 8 | row[1] as usize;
+            assert_eq!(read, len);
+            assert_eq!(row[2..2 + read], data[..]);
+        }
+    }
+}
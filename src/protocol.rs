@@ -1,13 +1,86 @@
 use ansi_term::Color::Green;
 use ansi_term::Colour::Red;
-use reed_solomon::Encoder;
 
 use crate::{
-    controls, fatal, info, nibble,
-    utilities::{nibbles_to_bytes, split_u16},
+    consts::MAX_SIZE,
+    controls::{data_symbols, Control, Symbol, UnknownControl},
+    debug,
+    encoder::clock_symbol,
+    fec::{Fec, FecScheme, ReedSolomon},
+    interleave::{Interleaver, FILLER},
+    outer::OuterCode,
+    utilities::{crc8, make_transmission, nibbles_to_symbols, split_u16},
+    warn,
 };
 
-#[derive(Debug, PartialEq, Eq)]
+/// Header format sent. Version 1 had no version field, no packet flags and no checksum,
+/// its packet headers declared the data size in nibbles (3 per byte). It is still decoded.
+pub const VERSION: u8 = 2;
+
+// flags of the transmission header, the upper nibble holds the version (0 in version 1)
+/// Version 1 only, later versions carry a message type
+const ENQUIRY: u8 = 0b01;
+/// Group size and parity count of the outer code follow the packet count
+const OUTER_CODE: u8 = 0b10;
+/// Scheme and parameter of the packet FEC follow, without it packets use the default
+const FEC: u8 = 0b100;
+/// Depth and row length of the block interleaver follow
+const INTERLEAVED: u8 = 0b1000;
+
+/// The header is read before its packets' scheme is known, it always uses RS
+const HEADER_FEC: ReedSolomon = ReedSolomon::Fixed(4);
+
+/// Kind of a transmission, tells the receiver what its packets mean.
+/// Receivers skip types they don't know, so new ones can be added.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Message {
+    /// The sender's data
+    Data,
+    /// Packets carry IDs of the receiver's data to resend
+    Enquiry,
+    /// The receiver's data arrived completely, no packets
+    Ack,
+    /// Like an enquiry, while the receiver's data is still being sent
+    Nak,
+    /// Announces the sender, no packets
+    Hello,
+    /// The sender gives up the transfer, no packets
+    Abort,
+    /// Packets carry information about the data, e.g. a file name
+    Metadata,
+    /// A type added after this side was built, the frame is skipped
+    Unknown(u8),
+}
+
+impl Message {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Self::Data => 0,
+            Self::Enquiry => 1,
+            Self::Ack => 2,
+            Self::Nak => 3,
+            Self::Hello => 4,
+            Self::Abort => 5,
+            Self::Metadata => 6,
+            Self::Unknown(byte) => byte,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Self {
+        match byte {
+            0 => Self::Data,
+            1 => Self::Enquiry,
+            2 => Self::Ack,
+            3 => Self::Nak,
+            4 => Self::Hello,
+            5 => Self::Abort,
+            6 => Self::Metadata,
+            byte => Self::Unknown(byte),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum State {
     Normal,
     WaitingForResponse,
@@ -15,8 +88,14 @@ pub enum State {
 
 #[derive(Debug, Clone)]
 pub struct TransmissionHeader {
-    pub is_enquiry: bool,
+    /// Format of this header and its packet headers
+    pub version: u8,
+    pub message: Message,
+    /// Data packets, parity packets of the outer code are not counted
     pub total_packets: u16,
+    pub outer_code: Option<OuterCode>,
+    pub fec: FecScheme,
+    pub interleaver: Option<Interleaver>,
     pub ecc: Vec<u8>, // 4 bytes to safe 2 bytes
 }
 
@@ -28,9 +107,16 @@ pub struct Transmission {
 
 #[derive(Debug, Clone)]
 pub struct PacketHeader {
-    pub size: u16,
+    pub version: u8,
+    /// Reserved, unknown flags are ignored
+    pub flags: u8,
+    /// Data bytes
+    pub len: u16,
     pub id: u16,
-    pub ecc_size: u8,
+    /// ECC bytes of all codewords together
+    pub ecc_size: u16,
+    /// Number of interleaved codewords header and data are split into
+    pub codewords: u8,
 }
 
 #[derive(Debug, Clone)]
@@ -38,163 +124,620 @@ pub struct Packet {
     pub header: PacketHeader,
     pub data: Vec<u8>,
     pub ecc: Vec<u8>,
+    /// Positions in header, data and ECC that arrived suspect, decoded as erasures
+    pub erasures: Vec<usize>,
 }
 
 impl PacketHeader {
-    pub fn new(size: u16, id: u16, ecc_size: u8) -> Self {
-        Self { size, id, ecc_size }
+    pub fn new(len: u16, id: u16, ecc_size: u16, codewords: u8) -> Self {
+        Self {
+            version: VERSION,
+            flags: 0,
+            len,
+            id,
+            ecc_size,
+            codewords,
+        }
+    }
+
+    /// Bytes from SOH up to SOTX, which starts the data chunk
+    pub fn chunk_len(version: u8) -> usize {
+        if version == 1 {
+            6
+        } else {
+            11
+        }
     }
 
     #[allow(clippy::cast_possible_truncation)]
     pub fn to_vec(&self) -> Vec<u8> {
-        vec![
-            controls::SOH,
-            (self.size >> 8) as u8,
-            self.size as u8,
-            (self.id >> 8) as u8,
-            self.id as u8,
-            self.ecc_size,
-            controls::SOTX,
-        ]
+        let mut bytes = vec![Control::Soh.code()];
+        if self.version == 1 {
+            // data size encoded is 3 times the size of the data, 1 raw byte == 3 encoded nibbles
+            bytes.extend(split_u16(self.len * 3));
+            bytes.extend(split_u16(self.id));
+            bytes.push(self.ecc_size as u8);
+        } else {
+            bytes.extend([self.version, self.flags]);
+            bytes.extend(split_u16(self.len));
+            bytes.extend(split_u16(self.id));
+            bytes.extend(split_u16(self.ecc_size));
+            bytes.push(self.codewords);
+            bytes.push(crc8(&bytes[1..]));
+        }
+        bytes.push(Control::Sotx.code());
+        bytes
     }
 
-    #[allow(clippy::cast_possible_truncation)]
-    pub fn to_binary(&self) -> Vec<(u8, bool)> {
-        vec![
-            (controls::SOH, true),
-            ((self.size >> 8) as u8, false),
-            (self.size as u8, false),
-            ((self.id >> 8) as u8, false),
-            (self.id as u8, false),
-            (self.ecc_size, false),
-            (controls::SOTX, true),
-        ]
+    /// Parses the header chunk (SOH up to SOTX) of a packet in `version` format.
+    /// Version 2 headers are checked against their checksum, so a damaged length
+    /// is never used to slice the data.
+    pub fn parse(chunk: &[u8], version: u8) -> Option<Self> {
+        let chunk = chunk.get(..Self::chunk_len(version))?;
+        if chunk[0] != Control::Soh.code() {
+            return None;
+        }
+        let u16_at = |at: usize| (chunk[at] as u16) << 8 | (chunk[at + 1] as u16);
+        let header = if version == 1 {
+            // one codeword, with one and a half times the data size as ECC
+            let (size, ecc_size) = (u16_at(1), chunk[5]);
+            let len = size / 3;
+            if size % 3 != 0 || ecc_size != (len as usize * 3 / 2) as u8 {
+                debug!("Invalid Header: {chunk:?}");
+                return None;
+            }
+            Self {
+                version,
+                flags: 0,
+                len,
+                id: u16_at(3),
+                ecc_size: ecc_size as u16,
+                codewords: 1,
+            }
+        } else {
+            let (protected, checksum) = chunk[1..].split_at(chunk.len() - 2);
+            if crc8(protected) != checksum[0] || chunk[1] != version {
+                debug!("Packet header checksum mismatch: {chunk:?}");
+                return None;
+            }
+            Self {
+                version,
+                flags: chunk[2],
+                len: u16_at(3),
+                id: u16_at(5),
+                ecc_size: u16_at(7),
+                codewords: chunk[9],
+            }
+        };
+        if header.codewords == 0 {
+            debug!("Invalid Header: {chunk:?}");
+            return None;
+        }
+        Some(header)
+    }
+
+    pub fn to_binary(&self) -> Vec<Symbol> {
+        let bytes = self.to_vec();
+        let mut binary = vec![Symbol::Control(Control::Soh)];
+        binary.extend(data_symbols(&bytes[1..bytes.len() - 1]));
+        binary.push(Symbol::Control(Control::Sotx));
+        binary
     }
 
     pub fn empty() -> Self {
+        Self::new(0, 0, 0, 1)
+    }
+}
+
+/// Header and data of a packet are dealt round-robin into codewords of at most
+/// [`MAX_SIZE`] bytes with their ECC, and the ECC is interleaved the same way.
+/// A burst on the wire thus hits every codeword with a few bytes only.
+struct Layout {
+    /// (codeword, index in it) of every message byte, then of every ECC byte
+    message: Vec<(usize, usize)>,
+    ecc: Vec<(usize, usize)>,
+    message_lens: Vec<usize>,
+    ecc_lens: Vec<usize>,
+}
+
+impl Layout {
+    fn new(message_len: usize, codewords: usize, fec: &dyn Fec) -> Self {
+        let message_lens: Vec<usize> = (0..codewords)
+            .map(|codeword| (message_len + codewords - 1 - codeword) / codewords)
+            .collect();
+        let ecc_lens: Vec<usize> = message_lens.iter().map(|&len| fec.overhead(len)).collect();
         Self {
-            size: 0,
-            id: 0,
-            ecc_size: 0,
+            message: round_robin(&message_lens),
+            ecc: round_robin(&ecc_lens),
+            message_lens,
+            ecc_lens,
+        }
+    }
+
+    /// Fewest codewords that keep each one within `MAX_SIZE`, `None` if 255 are not enough
+    fn fitting(message_len: usize, fec: &dyn Fec) -> Option<Self> {
+        (1..=u8::MAX as usize)
+            .map(|codewords| Self::new(message_len, codewords, fec))
+            .find(|layout| layout.longest() <= MAX_SIZE as usize)
+    }
+
+    fn longest(&self) -> usize {
+        self.message_lens
+            .iter()
+            .zip(&self.ecc_lens)
+            .map(|(message, ecc)| message + ecc)
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn split(&self, bytes: &[u8], positions: &[(usize, usize)], lens: &[usize]) -> Vec<Vec<u8>> {
+        let mut codewords: Vec<Vec<u8>> = lens.iter().map(|&len| vec![0; len]).collect();
+        for (&byte, &(codeword, at)) in bytes.iter().zip(positions) {
+            codewords[codeword][at] = byte;
         }
+        codewords
     }
+
+    fn join(codewords: &[Vec<u8>], positions: &[(usize, usize)]) -> Vec<u8> {
+        positions
+            .iter()
+            .map(|&(codeword, at)| codewords[codeword][at])
+            .collect()
+    }
+}
+
+/// Positions of the bytes when dealing round-robin into parts of `lens`, which don't grow
+fn round_robin(lens: &[usize]) -> Vec<(usize, usize)> {
+    let rounds = lens.first().copied().unwrap_or(0);
+    (0..rounds)
+        .flat_map(|at| {
+            lens.iter()
+                .enumerate()
+                .filter(move |(_, &len)| at < len)
+                .map(move |(codeword, _)| (codeword, at))
+        })
+        .collect()
 }
 
 impl Packet {
-    pub fn new(packet_data: Vec<u8>, id: u16) -> Self {
+    pub fn new(packet_data: Vec<u8>, id: u16) -> Option<Self> {
+        Self::with_fec(packet_data, id, &*FecScheme::default().codec())
+    }
+
+    /// ECC over header and data from `fec`, split into as many codewords as needed.
+    /// `None` if the packet is too long for them.
+    pub fn with_fec(packet_data: Vec<u8>, id: u16, fec: &dyn Fec) -> Option<Self> {
         let data_size = packet_data.len();
-        let ecc_size = (data_size as f32 * 1.5) as usize;
-        // data size encoded is 3 times the size of the data, 1 raw byte == 3 encoded nibbles
-        let header = PacketHeader::new((data_size * 3) as u16, id, ecc_size as u8);
+        let message_len = PacketHeader::empty().to_vec().len() + data_size;
+        let layout = Layout::fitting(message_len, fec)?;
+        let ecc_size: usize = layout.ecc_lens.iter().sum();
+        if data_size > u16::MAX as usize || ecc_size > u16::MAX as usize {
+            return None;
+        }
+        let header = PacketHeader::new(
+            data_size as u16,
+            id,
+            ecc_size as u16,
+            layout.message_lens.len() as u8,
+        );
 
         let mut complete_data = Vec::new();
         complete_data.append(&mut header.to_vec());
         complete_data.append(&mut packet_data.clone());
 
-        let encoded = Encoder::new(ecc_size).encode(&complete_data[..]);
+        let eccs: Vec<Vec<u8>> = layout
+            .split(&complete_data, &layout.message, &layout.message_lens)
+            .iter()
+            .map(|codeword| fec.encode(codeword))
+            .collect();
 
-        Self {
+        Some(Self {
             header,
             data: packet_data,
-            ecc: encoded.ecc().to_vec(),
-        }
+            ecc: Layout::join(&eccs, &layout.ecc),
+            erasures: Vec::new(),
+        })
+    }
+
+    pub fn from_binary(symbols: &[Symbol]) -> Vec<Self> {
+        Self::from_stream(symbols, &[], VERSION)
     }
 
-    pub fn from_binary(data: Vec<Vec<u8>>) -> Vec<Self> {
+    /// Reads the packets from the symbols following the transmission header, with packet
+    /// headers in `version` format. `suspects` flags the symbols that arrived suspect.
+    ///
+    /// Every packet is read as long as its header declares, control flags within it don't
+    /// matter. After a header that can't be parsed, the next valid SOH starts over.
+    /// A packet cut short is filled up, the missing bytes become erasures.
+    pub fn from_stream(symbols: &[Symbol], suspects: &[bool], version: u8) -> Vec<Self> {
         let mut packets: Vec<Packet> = Vec::new();
+        let suspect = |at: usize| suspects.get(at).copied().unwrap_or(false);
+        let byte_range = |from: usize, to: usize| -> Vec<u8> {
+            symbols[from.min(symbols.len())..to.min(symbols.len())]
+                .iter()
+                .map(|symbol| symbol.byte())
+                .collect()
+        };
+        let header_len = PacketHeader::chunk_len(version);
+        // symbols passed over looking for a header, filler of interleaved rows aside
+        let mut skipped = 0;
 
-        for i in 0..data.len() {
-            if let (Some(header), Some(bytes)) = (data.get(i), data.get(i + 1)) {
-                if let (
-                    Some(&control),
-                    Some(&size_high),
-                    Some(&size_low),
-                    Some(&id_high),
-                    Some(&id_low),
-                    Some(&ecc_size),
-                ) = (
-                    header.first(),
-                    header.get(1),
-                    header.get(2),
-                    header.get(3),
-                    header.get(4),
-                    header.get(5),
-                ) {
-                    if control != controls::SOH {
-                        continue;
-                    }
-                    let size: u16 = (size_high as u16) << 8 | (size_low as u16);
-                    let id: u16 = (id_high as u16) << 8 | (id_low as u16);
-                    let pack_header = PacketHeader { size, id, ecc_size };
-
-                    if let Some(data) = bytes.get(1..(size / 3 + 1) as usize) {
-                        if let Some(ecc) = bytes.get((size / 3 + 1) as usize..) {
-                            let packet = Packet {
-                                header: pack_header,
-                                data: data.to_vec(),
-                                ecc: ecc.to_vec(),
-                            };
-                            packets.push(packet);
-                        }
-                    } else {
-                        info!("Invalid Header: {header:?}");
-                    }
-                } else {
-                    let data1 = data.get(i);
-                    let data2 = data.get(i + 1);
-                    info!("Could not parse packet header: {data1:?}\n{data2:?}");
+        let mut at = 0;
+        while at < symbols.len() {
+            match symbols[at] {
+                Symbol::Control(Control::Eot) => break,
+                Symbol::Control(Control::Nac) => {
+                    let count = symbols.get(at + 1).map_or(0, |symbol| symbol.byte());
+                    at += Nak::symbol_count(count as usize);
+                    continue;
                 }
+                _ => {}
+            }
+            // without a checksum (version 1) only a flagged SOH is trusted to start a packet
+            let candidate = symbols[at].byte() == Control::Soh.code()
+                && (symbols[at].is_control() || version > 1);
+            let header = candidate
+                .then(|| byte_range(at, at + header_len))
+                .and_then(|chunk| PacketHeader::parse(&chunk, version));
+            let Some(pack_header) = header else {
+                if symbols[at] != FILLER {
+                    skipped += 1;
+                }
+                at += 1;
+                continue;
+            };
+            if skipped > 0 {
+                debug!("Resynchronised on SOH after {skipped} symbols");
+                skipped = 0;
+            }
+
+            // SOTX, data and ECC; the header bytes before SOTX come first in the codeword
+            let body_start = at + header_len;
+            let data_end = pack_header.len as usize + 1;
+            let expected = data_end + pack_header.ecc_size as usize;
+            let mut body = byte_range(body_start, body_start + expected);
+            let mut erasures: Vec<usize> = (0..header_len)
+                .filter(|&i| suspect(at + i))
+                .chain(
+                    (0..body.len())
+                        .filter(|&i| suspect(body_start + i))
+                        .map(|i| header_len + i),
+                )
+                .collect();
+            if body.len() < expected {
+                erasures.extend((body.len()..expected).map(|i| header_len + i));
             }
+            body.resize(expected, 0);
+            packets.push(Packet {
+                header: pack_header,
+                data: body[1..data_end].to_vec(),
+                ecc: body[data_end..].to_vec(),
+                erasures,
+            });
+            at = body_start + expected;
+        }
+        if skipped > 0 {
+            debug!("Skipped {skipped} symbols without a packet header");
         }
         packets
     }
 
-    pub fn to_binary(&self) -> Vec<(u8, bool)> {
-        let mut binary: Vec<(u8, bool)> = Vec::new();
+    pub fn to_binary(&self) -> Vec<Symbol> {
+        let mut binary: Vec<Symbol> = Vec::new();
         binary.append(&mut self.header.to_binary());
-        binary.append(&mut self.data.iter().map(|byte| (*byte, false)).collect());
-        binary.append(&mut self.ecc.iter().map(|byte| (*byte, false)).collect());
+        binary.extend(data_symbols(&self.data));
+        binary.extend(data_symbols(&self.ecc));
         binary
     }
 
+    /// Declared data length in bytes
     pub fn set_size(&mut self, new_size: u16) {
-        self.header.size = new_size;
+        self.header.len = new_size;
+    }
+
+    /// Checks header and data against the ECC of `fec`, codeword by codeword,
+    /// suspect bytes are decoded as erasures.
+    /// Returns the repaired packet and the number of corrected bytes, `None` if it is beyond repair.
+    pub fn repair(&self, fec: &dyn Fec) -> Option<(Packet, usize)> {
+        // version 1 packets are one Reed-Solomon codeword with the ECC size they declare
+        let fixed = ReedSolomon::Fixed(self.header.ecc_size as usize);
+        let fec: &dyn Fec = if self.header.version == 1 {
+            &fixed
+        } else {
+            fec
+        };
+        let header_len = self.header.to_vec().len();
+        let mut message = self.header.to_vec();
+        message.extend(&self.data);
+        let layout = Layout::new(message.len(), self.header.codewords as usize, fec);
+        if layout.ecc.len() != self.ecc.len() {
+            debug!("Packet {} has a damaged layout", self.header.id);
+            return None;
+        }
+        if self.header.version != 1 && layout.longest() > MAX_SIZE as usize {
+            warn!("Packet too long: {}", layout.longest());
+            return None;
+        }
+
+        let mut codewords = layout.split(&message, &layout.message, &layout.message_lens);
+        let mut eccs = layout.split(&self.ecc, &layout.ecc, &layout.ecc_lens);
+        let mut erasures: Vec<Vec<u8>> = vec![Vec::new(); codewords.len()];
+        for &at in &self.erasures {
+            let (codeword, at) = match layout.message.get(at) {
+                Some(&(codeword, at)) => (codeword, at),
+                None => {
+                    let Some(&(codeword, at)) = layout.ecc.get(at - message.len()) else {
+                        continue;
+                    };
+                    (codeword, layout.message_lens[codeword] + at)
+                }
+            };
+            erasures[codeword].push(at as u8);
+        }
+        let mut errors = 0;
+        for ((codeword, ecc), erasures) in codewords.iter_mut().zip(&mut eccs).zip(&erasures) {
+            errors += fec.decode(codeword, ecc, erasures)?;
+        }
+
+        let repaired = Layout::join(&codewords, &layout.message);
+        let header = PacketHeader {
+            id: PacketHeader::parse(&repaired, self.header.version)?.id,
+            ..self.header.clone()
+        };
+        let packet = Packet {
+            header,
+            data: repaired[header_len..].to_vec(),
+            ecc: Layout::join(&eccs, &layout.ecc),
+            erasures: Vec::new(),
+        };
+        Some((packet, errors))
     }
 }
 
 impl TransmissionHeader {
-    pub fn new(size: u16, is_enquiry: bool) -> Self {
-        let encoded = Encoder::new(4).encode(&split_u16(size)[..]);
-        Self {
-            is_enquiry,
+    pub fn new(size: u16, message: Message) -> Self {
+        let mut header = Self {
+            version: VERSION,
+            message,
             total_packets: size,
-            ecc: encoded.ecc().to_vec(),
+            outer_code: None,
+            fec: FecScheme::default(),
+            interleaver: None,
+            ecc: Vec::new(),
+        };
+        header.seal();
+        header
+    }
+
+    /// Data transmission of `size` packets protected by `fec`,
+    /// with an outer code each group is followed by parity packets
+    pub fn data(size: u16, fec: FecScheme, outer_code: Option<OuterCode>) -> Self {
+        let mut header = Self::new(size, Message::Data);
+        header.fec = fec;
+        header.outer_code = outer_code;
+        header.seal();
+        header
+    }
+
+    /// Recomputes the ECC after a field changed
+    fn seal(&mut self) {
+        self.ecc = HEADER_FEC.encode(&self.protected());
+    }
+
+    /// Parses the header chunk starting with SOT, repairing the packet count with its ECC.
+    /// `suspects` flags bytes of `chunk` to decode as erasures.
+    /// `None` if it is too short or beyond repair.
+    pub fn from_bytes(chunk: &[u8], suspects: &[bool]) -> Option<Self> {
+        if chunk.len() < 8 || chunk[0] != Control::Sot.code() {
+            return None;
+        }
+        let flags = chunk[1] & 0xF;
+        let version = match chunk[1] >> 4 {
+            0 => 1,
+            VERSION => VERSION,
+            _ => {
+                debug!("Unknown header version {}", chunk[1] >> 4);
+                return None;
+            }
+        };
+        let has_outer_code = flags & OUTER_CODE != 0;
+        let has_fec = flags & FEC != 0;
+        let is_interleaved = flags & INTERLEAVED != 0;
+        let fields = (version != 1) as usize
+            + 2
+            + 2 * has_outer_code as usize
+            + 2 * has_fec as usize
+            + 3 * is_interleaved as usize;
+        // the header ECC covers the packet count and the optional fields,
+        // since version 2 the flags as well, which tell how many fields there are
+        let start = if version == 1 { 2 } else { 1 };
+        let protected = 2 + fields - start;
+        let ecc_len = HEADER_FEC.overhead(protected);
+        let mut repaired = chunk.get(start..start + protected)?.to_vec();
+        let mut ecc = chunk
+            .get(start + protected..start + protected + ecc_len)?
+            .to_vec();
+        let erasures: Vec<u8> = (start..start + protected + ecc_len)
+            .filter(|&at| suspects.get(at).copied().unwrap_or(false))
+            .map(|at| (at - start) as u8)
+            .collect();
+        HEADER_FEC.decode(&mut repaired, &mut ecc, &erasures)?;
+        if version != 1 && repaired.remove(0) != chunk[1] {
+            debug!("Transmission header flags damaged: {chunk:?}");
+            return None;
         }
+        let message = if version == 1 {
+            None
+        } else {
+            Some(Message::from_byte(repaired.remove(0)))
+        };
+        let total_packets: u16 = (repaired[0] as u16) << 8 | (repaired[1] as u16);
+        // version 1 knew data and enquiries, an enquiry without packets being the ack
+        let message = message.unwrap_or(match flags & ENQUIRY != 0 {
+            true if total_packets == 0 => Message::Ack,
+            true => Message::Enquiry,
+            false => Message::Data,
+        });
+        let mut rest = &repaired[2..];
+        let mut outer_code = None;
+        if has_outer_code {
+            outer_code = Some(OuterCode::try_new(rest[0], rest[1])?);
+            rest = &rest[2..];
+        }
+        let mut fec = FecScheme::default();
+        if has_fec {
+            fec = FecScheme::from_bytes([rest[0], rest[1]])?;
+            rest = &rest[2..];
+        }
+        let mut interleaver = None;
+        if is_interleaved {
+            let row_len = (rest[1] as u16) << 8 | (rest[2] as u16);
+            interleaver = Some(Interleaver::try_new(rest[0], row_len)?);
+        }
+        Some(Self {
+            version,
+            message,
+            total_packets,
+            outer_code,
+            fec,
+            interleaver,
+            ecc,
+        })
     }
 
-    #[allow(clippy::cast_possible_truncation)]
-    fn to_binary(&self) -> Vec<(u8, bool)> {
-        let mut binary = vec![
-            (controls::SOT, true),
-            (self.is_enquiry as u8, false),
-            ((self.total_packets >> 8) as u8, false),
-            (self.total_packets as u8, false),
-        ];
-        binary.append(&mut self.ecc.iter().map(|byte| (*byte, false)).collect());
+    /// Bytes covered by the ECC
+    fn protected(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        if self.version != 1 {
+            bytes.push(self.flags());
+        }
+        bytes.extend(self.fields());
+        bytes
+    }
+
+    /// Message type, packet count and the optional fields the flags announce
+    fn fields(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        if self.version != 1 {
+            bytes.push(self.message.to_byte());
+        }
+        bytes.extend(split_u16(self.total_packets));
+        if let Some(code) = self.outer_code {
+            bytes.extend([code.data, code.parity]);
+        }
+        if self.fec != FecScheme::default() {
+            bytes.extend(self.fec.to_bytes());
+        }
+        if let Some(interleaver) = self.interleaver {
+            bytes.push(interleaver.depth);
+            bytes.extend(split_u16(interleaver.row_len));
+        }
+        bytes
+    }
+
+    /// Number of symbols incl. SOT
+    pub fn symbol_count(&self) -> usize {
+        2 + self.fields().len() + self.ecc.len()
+    }
+
+    fn flags(&self) -> u8 {
+        let mut flags = if self.version == 1 {
+            0
+        } else {
+            self.version << 4
+        };
+        if self.version == 1 && matches!(self.message, Message::Enquiry | Message::Ack) {
+            flags |= ENQUIRY;
+        }
+        if self.outer_code.is_some() {
+            flags |= OUTER_CODE;
+        }
+        if self.fec != FecScheme::default() {
+            flags |= FEC;
+        }
+        if self.interleaver.is_some() {
+            flags |= INTERLEAVED;
+        }
+        flags
+    }
+
+    pub fn to_binary(&self) -> Vec<Symbol> {
+        let mut binary = vec![Symbol::Control(Control::Sot), Symbol::Data(self.flags())];
+        binary.extend(data_symbols(&self.fields()));
+        binary.extend(data_symbols(&self.ecc));
         binary
     }
 }
 
 impl Transmission {
-    pub fn new(data: Vec<Packet>, is_enquiry: bool) -> Self {
+    pub fn new(data: Vec<Packet>, message: Message) -> Self {
         Self {
-            header: TransmissionHeader::new(data.len() as u16, is_enquiry),
+            header: TransmissionHeader::new(data.len() as u16, message),
             packets: data,
         }
     }
 
+    /// Data transmission of `chunks`, one packet each protected by `fec`.
+    /// With `outer_code` parity packets follow every group of them.
+    /// `None` if the packets would run out of `u16` IDs or a chunk is too long for 255 codewords.
+    pub fn from_chunks(
+        chunks: Vec<Vec<u8>>,
+        fec: FecScheme,
+        outer_code: Option<OuterCode>,
+    ) -> Option<Self> {
+        let parity_packets = outer_code.map_or(0, |code| {
+            chunks.len().div_ceil(code.data as usize) * code.parity as usize
+        });
+        if chunks.len() + parity_packets >= u16::MAX as usize {
+            return None;
+        }
+        let codec = fec.codec();
+        let packets = make_transmission(chunks, &*codec)?;
+        Some(Self {
+            header: TransmissionHeader::data(packets.len() as u16, fec, outer_code),
+            packets: match outer_code {
+                Some(outer_code) => outer_code.encode(packets, &*codec)?,
+                None => packets,
+            },
+        })
+    }
+
+    /// Sends the packets interleaved in blocks of `depth`, 1 leaves them as they are
+    pub fn interleaved(mut self, depth: u8) -> Self {
+        let row_len = self
+            .packets
+            .iter()
+            .map(|packet| packet.to_binary().len())
+            .max()
+            .unwrap_or(0);
+        assert!(
+            row_len <= u16::MAX as usize,
+            "packets too long to interleave"
+        );
+        self.header.interleaver = Interleaver::try_new(depth, row_len as u16);
+        self.header.seal();
+        self
+    }
+
+    /// Nothing is missing, the peer's data arrived completely
+    pub fn ack() -> Self {
+        Self::new(Vec::new(), Message::Ack)
+    }
+
+    /// Header of a data transmission with `total_packets` but no packets.
+    /// The peer answers it with an enquiry for everything it is missing, or an ack.
+    pub fn probe(total_packets: u16) -> Self {
+        Self {
+            header: TransmissionHeader::new(total_packets, Message::Data),
+            packets: Vec::new(),
+        }
+    }
+
+    pub fn is_ack(&self) -> bool {
+        self.header.message == Message::Ack
+    }
+
     pub fn from_bytes(data: Vec<u8>) {
-        //  , byte_map: HashMap<u8, &str>
         let mut decoder = ProtocolDecoder::new(data);
         decoder.decode();
     }
@@ -204,47 +747,62 @@ impl Transmission {
         self.packets = packets;
     }
 
-    fn create_start() -> Vec<(u8, bool)> {
-        let encoded = Encoder::new(2).encode(&[controls::SOT]);
-        let mut binary: Vec<(u8, bool)> = Vec::new();
-        binary.push((controls::SOT, true));
-        binary.append(&mut encoded.ecc().iter().map(|byte| (*byte, false)).collect());
+    fn create_start() -> Vec<Symbol> {
+        let ecc = ReedSolomon::Fixed(2).encode(&[Control::Sot.code()]);
+        let mut binary: Vec<Symbol> = vec![Symbol::Control(Control::Sot)];
+        binary.extend(data_symbols(&ecc));
         binary
     }
 
+    /// Number of symbols (byte + control flag) the transmission is encoded to
+    pub fn symbol_count(&self) -> usize {
+        let header = self.header.symbol_count();
+        let packets: usize = match self.header.interleaver {
+            Some(interleaver) => {
+                self.packets.len().div_ceil(interleaver.depth as usize) * interleaver.block_len()
+            }
+            None => self
+                .packets
+                .iter()
+                .map(|packet| packet.header.to_vec().len() + packet.data.len() + packet.ecc.len())
+                .sum(),
+        };
+        header + packets + 1
+    }
+
+    /// Symbols grouped into transmission header, one segment per packet (or interleaved block) and EOT
+    pub fn into_segments(self) -> impl Iterator<Item = Vec<Symbol>> {
+        std::iter::once(self.header.to_binary())
+            .chain(Self::packet_segments(self.header.interleaver, self.packets))
+            .chain(std::iter::once(vec![Symbol::Control(Control::Eot)]))
+    }
+
+    /// One segment per packet, or per block of packets if they are interleaved
+    pub fn packet_segments(
+        interleaver: Option<Interleaver>,
+        packets: Vec<Packet>,
+    ) -> Vec<Vec<Symbol>> {
+        let rows: Vec<Vec<Symbol>> = packets.iter().map(Packet::to_binary).collect();
+        match interleaver {
+            Some(interleaver) => rows
+                .chunks(interleaver.depth as usize)
+                .map(|block| interleaver.interleave(block))
+                .collect(),
+            None => rows,
+        }
+    }
+
     /// C = clock, I = is_control, D = data
     /// CDDD
     /// CDDD
     /// CDDI
     pub fn to_binary(&self) -> Vec<u8> {
-        let mut binary: Vec<(u8, bool)> = Vec::new();
-
-        binary.extend(self.header.to_binary());
-        binary.extend(
-            self.packets
-                .iter()
-                .flat_map(|packet| packet.to_binary())
-                .collect::<Vec<(u8, bool)>>(),
-        );
-        binary.push((controls::EOT, true));
-
-        // dbg!(&binary);
-
         let mut clock: u8 = 0b0;
         let mut buffer: Vec<u8> = Vec::new();
 
-        for mapped_byte in binary {
-            let byte = mapped_byte.0;
-            let is_control = mapped_byte.1;
-            let one: u8 = (clock << 3) | (nibble!(byte >> 4).1 >> 1);
+        for symbol in self.clone().into_segments().flatten() {
+            buffer.extend(clock_symbol(symbol, clock));
             clock ^= 1;
-            let two: u8 = (clock << 3) | (nibble!(byte >> 2).1 & 0b0111);
-            clock ^= 1;
-            let three: u8 = (clock << 3) | (nibble!(byte << 1).1 & 0b110) | u8::from(is_control);
-            clock ^= 1;
-            buffer.push(one);
-            buffer.push(two);
-            buffer.push(three);
         }
 
         let mut result = Vec::new();
@@ -264,9 +822,71 @@ impl Transmission {
     }
 }
 
+/// Most IDs a single NAK carries, longer gaps take several
+pub const NAK_MAX_IDS: usize = 16;
+const NAK_FEC: ReedSolomon = ReedSolomon::Fixed(4);
+
+/// Negative acknowledgement, sent while the peer's transmission is still running:
+/// NAC, ID count, the IDs and ECC over count and IDs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Nak {
+    pub ids: Vec<u16>,
+}
+
+impl Nak {
+    /// Keeps the first `NAK_MAX_IDS` of `ids`
+    pub fn new(mut ids: Vec<u16>) -> Self {
+        ids.truncate(NAK_MAX_IDS);
+        Self { ids }
+    }
+
+    /// Symbols of a NAK with `count` IDs, NAC included
+    pub fn symbol_count(count: usize) -> usize {
+        2 + count * 2 + NAK_FEC.overhead(count * 2 + 1)
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn payload(&self) -> Vec<u8> {
+        let mut payload = vec![self.ids.len() as u8];
+        for id in &self.ids {
+            payload.extend(split_u16(*id));
+        }
+        payload
+    }
+
+    pub fn to_binary(&self) -> Vec<Symbol> {
+        let payload = self.payload();
+        let mut binary = vec![Symbol::Control(Control::Nac)];
+        binary.extend(data_symbols(&payload));
+        binary.extend(data_symbols(&NAK_FEC.encode(&payload)));
+        binary
+    }
+
+    /// Parses the bytes following NAC, `None` if they are incomplete or beyond repair
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let count = *bytes.first()? as usize;
+        if count > NAK_MAX_IDS || bytes.len() < Self::symbol_count(count) - 1 {
+            return None;
+        }
+        let mut repaired = bytes[..count * 2 + 1].to_vec();
+        let mut ecc = bytes[count * 2 + 1..Self::symbol_count(count) - 1].to_vec();
+        NAK_FEC.decode(&mut repaired, &mut ecc, &[])?;
+        // the count itself may have been repaired
+        if repaired[0] as usize != count {
+            return None;
+        }
+        let ids = repaired[1..]
+            .chunks(2)
+            .map(|id| (id[0] as u16) << 8 | (id[1] as u16))
+            .collect();
+        Some(Self { ids })
+    }
+}
+
 pub struct ProtocolDecoder {
-    bytes: Vec<u8>,
-    flags: Vec<bool>,
+    symbols: Vec<Symbol>,
+    /// symbol arrived suspect: clock out of step or a control flag on a byte that is no control
+    suspects: Vec<bool>,
     #[allow(dead_code)]
     transmission: Option<Transmission>,
 }
@@ -274,17 +894,6 @@ pub struct ProtocolDecoder {
 impl ProtocolDecoder {
     /// data: raw data (ohne nullen aka full bytes )
     pub fn new(data: Vec<u8>) -> Self {
-        let byte_map = [
-            controls::SOT,
-            controls::EOT,
-            controls::SOH,
-            controls::SOTX,
-            // controls::EOTX,
-            //controls::ENQ,
-            //controls::ACK,
-            //controls::NAC,
-        ];
-
         let mut triplets = Vec::new();
 
         for chunk in data.chunks(3) {
@@ -297,32 +906,46 @@ impl ProtocolDecoder {
 
         let mut tuple_vec = Vec::new();
         for triplet in triplets {
-            let bytes = nibbles_to_bytes(triplet);
-            tuple_vec.push(bytes[0]);
-            tuple_vec.push(bytes[1]);
+            tuple_vec.extend(nibbles_to_symbols(triplet));
         }
 
-        let mut bytes = Vec::new();
-        let mut flags = Vec::new();
-
-        for tuple in tuple_vec {
-            bytes.push(tuple.0);
-            if byte_map.contains(&tuple.0) {
-                flags.push(tuple.1);
-            } else {
-                flags.push(false);
+        // the clock toggles with every nibble, a symbol with a nibble out of step is suspect
+        let first_clock = data.first().map_or(0, |byte| byte >> 7);
+        let mut out_of_step = vec![false; tuple_vec.len()];
+        for (i, byte) in data.iter().enumerate() {
+            for (j, nibble) in [byte >> 4, byte & 0xF].into_iter().enumerate() {
+                let n = i * 2 + j;
+                if (nibble >> 3) != first_clock ^ (n % 2) as u8 {
+                    out_of_step[n / 3] = true;
+                }
             }
         }
 
-        if bytes.last() == Some(&0) {
-            bytes.pop();
-            flags.pop();
+        let mut symbols = Vec::new();
+        let mut suspects = Vec::new();
+
+        for (symbol, out_of_step) in tuple_vec.into_iter().zip(out_of_step) {
+            match symbol {
+                Ok(symbol) => {
+                    symbols.push(symbol);
+                    suspects.push(out_of_step);
+                }
+                Err(UnknownControl(byte)) => {
+                    symbols.push(Symbol::Data(byte));
+                    suspects.push(true);
+                }
+            }
         }
 
+        // an odd number of symbols leaves half a triplet, not a symbol ending in 0
+        let len = data.len() * 2 / 3;
+        symbols.truncate(len);
+        suspects.truncate(len);
+
         /*eprint!("protokoll_bytes: [");
-        for i in 0..bytes.len() {
-            let byte = bytes[i].to_string();
-            if flags[i] {
+        for symbol in &symbols {
+            let byte = symbol.byte().to_string();
+            if symbol.is_control() {
                 eprint!("{}, ", Red.paint(byte));
             } else {
                 eprint!("{}, ", Green.paint(byte));
@@ -331,65 +954,280 @@ impl ProtocolDecoder {
         eprintln!("]");*/
 
         Self {
-            bytes, // real, decoded data
-            flags,
+            symbols, // real, decoded data
+            suspects,
             transmission: None,
         }
     }
 
-    pub fn decode(&mut self) -> Transmission {
-        let chunks = split_data(self.bytes.clone(), self.flags.clone());
-
-        let trans_header_chunks = chunks[0].clone();
-        let mut transmission_header: Option<TransmissionHeader> = None;
-        let chunk = trans_header_chunks;
-        if chunk[0] == controls::SOT {
-            let is_enquiry: bool = chunk[1] & 0b1 == 1;
-            //dbg!(&chunk);
-            let total_packets: u16 = (chunk[2] as u16) << 8 | (chunk[3] as u16);
-            let ecc: Vec<u8> = chunk[4..].to_vec();
-            transmission_header = Some(TransmissionHeader {
-                is_enquiry,
-                total_packets,
-                ecc,
-            });
+    /// Returns `None` if the transmission header is missing or too damaged to repair
+    pub fn decode(&mut self) -> Option<Transmission> {
+        if self.symbols.first() != Some(&Symbol::Control(Control::Sot)) {
+            warn!("Transmission header not found");
+            return None;
         }
-
-        if transmission_header.is_none() {
-            fatal!("Transmission header not found");
+        // the header runs up to the next control symbol
+        let chunk_len = 1 + self.symbols[1..]
+            .iter()
+            .take_while(|symbol| !symbol.is_control())
+            .count();
+        let chunk: Vec<u8> = self.symbols[..chunk_len]
+            .iter()
+            .map(|symbol| symbol.byte())
+            .collect();
+        let Some(transmission_header) =
+            TransmissionHeader::from_bytes(&chunk, &self.suspects[..chunk_len])
+        else {
+            warn!("Transmission header unrecoverable: {chunk:?}");
+            return None;
+        };
+        let header_len = transmission_header.symbol_count();
+        if let Some(interleaver) = transmission_header.interleaver {
+            self.deinterleave(interleaver, header_len);
         }
-        let packets: Vec<Packet> = Packet::from_binary(chunks[1..chunks.len() - 1].to_vec());
+
+        // packets are read by their declared length from where the header ends
+        let packets = Packet::from_stream(
+            self.symbols.get(header_len..).unwrap_or_default(),
+            self.suspects.get(header_len..).unwrap_or_default(),
+            transmission_header.version,
+        );
 
         let transmission: Transmission = Transmission {
-            header: transmission_header.unwrap(),
+            header: transmission_header,
             packets,
         };
-        transmission
+        Some(transmission)
+    }
+
+    /// Puts the packets after the header back in order, block by block.
+    /// NAKs between blocks and EOT stay as they are, a block cut short is filled with suspects.
+    fn deinterleave(&mut self, interleaver: Interleaver, header_len: usize) {
+        let mut symbols = self.symbols[..header_len.min(self.symbols.len())].to_vec();
+        let mut suspects = self.suspects[..symbols.len()].to_vec();
+        let mut at = symbols.len();
+        while at < self.symbols.len() {
+            let end = match self.symbols[at] {
+                Symbol::Control(Control::Eot) => self.symbols.len(),
+                Symbol::Control(Control::Nac) => {
+                    let count = self.symbols.get(at + 1).map_or(0, |symbol| symbol.byte());
+                    at + Nak::symbol_count(count as usize)
+                }
+                _ => {
+                    let end = (at + interleaver.block_len()).min(self.symbols.len());
+                    let block: Vec<(Symbol, bool)> = (at..end)
+                        .map(|i| (self.symbols[i], self.suspects[i]))
+                        .collect();
+                    for (symbol, suspect) in interleaver.deinterleave(&block, (FILLER, true)) {
+                        symbols.push(symbol);
+                        suspects.push(suspect);
+                    }
+                    at = end;
+                    continue;
+                }
+            }
+            .min(self.symbols.len());
+            symbols.extend(&self.symbols[at..end]);
+            suspects.extend(&self.suspects[at..end]);
+            at = end;
+        }
+        self.symbols = symbols;
+        self.suspects = suspects;
     }
 }
 
-/// Splits data at each control sequence
-fn split_data<T: Clone>(data: Vec<T>, flags: Vec<bool>) -> Vec<Vec<T>> {
-    assert_eq!(
-        data.len(),
-        flags.len(),
-        "Data and flags vectors must have the same length"
-    );
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fec::{Hamming, NoFec};
+
+    const MAX: usize = MAX_SIZE as usize;
+
+    /// What the version 1 encoder sent for the packets `b"Hi v1"` (ID 1) and `[0, 255, 7]`
+    /// (ID 2) of a data transmission, two nibbles per byte
+    const V1_FRAME: [u8; 63] = [
+        9, 120, 8, 8, 8, 12, 15, 75, 56, 124, 12, 8, 8, 56, 8, 11, 104, 8, 8, 40, 30, 8, 90, 40,
+        58, 41, 8, 61, 73, 74, 74, 77, 28, 88, 107, 72, 91, 108, 46, 77, 104, 11, 8, 8, 42, 8, 8,
+        12, 9, 8, 13, 8, 15, 126, 9, 108, 108, 56, 44, 104, 77, 104, 25,
+    ];
+
+    /// Splits a message and ECC of `len` bytes into codewords and joins them again
+    fn layout_round_trip(len: usize, fec: &dyn Fec) {
+        let layout = Layout::fitting(len, fec).unwrap();
+        assert!(layout.longest() <= MAX, "{len}: {}", layout.longest());
+        assert_eq!(layout.message.len(), len);
 
-    let mut chunks: Vec<Vec<T>> = Vec::new();
-    let mut current_chunk: Vec<T> = Vec::new();
+        let message: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        let codewords = layout.split(&message, &layout.message, &layout.message_lens);
+        let lens: Vec<usize> = codewords.iter().map(Vec::len).collect();
+        assert_eq!(lens, layout.message_lens);
+        assert_eq!(Layout::join(&codewords, &layout.message), message, "{len}");
 
-    for (value, flag) in data.into_iter().zip(flags.into_iter()) {
-        if flag && !current_chunk.is_empty() {
-            chunks.push(current_chunk);
-            current_chunk = Vec::new();
+        let ecc: Vec<u8> = (0..layout.ecc.len()).map(|i| !(i as u8)).collect();
+        let eccs = layout.split(&ecc, &layout.ecc, &layout.ecc_lens);
+        assert_eq!(Layout::join(&eccs, &layout.ecc), ecc, "{len}");
+    }
+
+    #[test]
+    fn layout_round_trips_around_max_size() {
+        for len in [0, 1, MAX - 1, MAX, MAX + 1] {
+            layout_round_trip(len, &ReedSolomon::Rate(130));
+            layout_round_trip(len, &Hamming);
+            layout_round_trip(len, &NoFec);
         }
-        current_chunk.push(value);
     }
 
-    if !current_chunk.is_empty() {
-        chunks.push(current_chunk);
+    #[test]
+    fn layout_splits_only_beyond_max_size() {
+        let codewords = |len| Layout::fitting(len, &NoFec).unwrap().message_lens.len();
+        assert_eq!(codewords(MAX), 1);
+        assert_eq!(codewords(MAX + 1), 2);
+        // ECC and message share a codeword
+        let fec = ReedSolomon::Fixed(8);
+        assert_eq!(
+            Layout::fitting(MAX - 8, &fec).unwrap().message_lens.len(),
+            1
+        );
+        assert_eq!(
+            Layout::fitting(MAX - 7, &fec).unwrap().message_lens.len(),
+            2
+        );
+    }
+
+    #[test]
+    fn too_long_for_255_codewords() {
+        assert!(Layout::fitting(255 * MAX, &NoFec).is_some());
+        assert!(Layout::fitting(255 * MAX + 1, &NoFec).is_none());
+        assert!(Packet::with_fec(vec![0; 255 * MAX], 1, &NoFec).is_none());
+        let chunks = vec![vec![0; 16], vec![0; 255 * MAX]];
+        assert!(Transmission::from_chunks(chunks, FecScheme::None, None).is_none());
+    }
+
+    #[test]
+    fn unknown_message_type_is_decoded() {
+        let header = TransmissionHeader::new(3, Message::Unknown(9));
+        let chunk: Vec<u8> = header
+            .to_binary()
+            .iter()
+            .map(|symbol| symbol.byte())
+            .collect();
+        let parsed = TransmissionHeader::from_bytes(&chunk, &[]).unwrap();
+        assert_eq!(parsed.message, Message::Unknown(9));
+        assert_eq!(parsed.total_packets, 3);
+    }
+
+    #[test]
+    fn decodes_a_version_1_frame() {
+        let transmission = ProtocolDecoder::new(V1_FRAME.to_vec()).decode().unwrap();
+        assert_eq!(transmission.header.version, 1);
+        assert_eq!(transmission.header.message, Message::Data);
+        assert_eq!(transmission.header.total_packets, 2);
+
+        let header = &transmission.packets[0].header;
+        assert_eq!((header.len, header.id, header.ecc_size), (5, 1, 7));
+        let (soh, sotx) = (Control::Soh.code(), Control::Sotx.code());
+        assert_eq!(header.to_vec(), [soh, 0, 15, 0, 1, 7, sotx]);
+
+        let fec = transmission.header.fec.codec();
+        let repaired: Vec<(Vec<u8>, usize)> = transmission
+            .packets
+            .iter()
+            .map(|packet| {
+                let (packet, errors) = packet.repair(&*fec).unwrap();
+                (packet.data, errors)
+            })
+            .collect();
+        assert_eq!(repaired, [(b"Hi v1".to_vec(), 0), (vec![0, 255, 7], 0)]);
+    }
+
+    #[test]
+    fn repairs_a_damaged_version_1_packet() {
+        let transmission = ProtocolDecoder::new(V1_FRAME.to_vec()).decode().unwrap();
+        let mut packet = transmission.packets[0].clone();
+        packet.data[1] ^= 0xFF;
+        let (packet, errors) = packet.repair(&*transmission.header.fec.codec()).unwrap();
+        assert_eq!((packet.data, errors), (b"Hi v1".to_vec(), 1));
     }
 
-    chunks
+    #[test]
+    fn version_1_header_round_trips() {
+        let mut header = PacketHeader::new(40, 513, 60, 1);
+        header.version = 1;
+        let bytes = header.to_vec();
+        assert_eq!(bytes.len(), PacketHeader::chunk_len(1) + 1);
+        let parsed = PacketHeader::parse(&bytes, 1).unwrap();
+        assert_eq!(
+            (parsed.len, parsed.id, parsed.ecc_size, parsed.codewords),
+            (40, 513, 60, 1)
+        );
+        // the ECC size follows the data size, anything else is damaged
+        let mut damaged = bytes.clone();
+        damaged[5] = 61;
+        assert!(PacketHeader::parse(&damaged, 1).is_none());
+        damaged = bytes;
+        damaged[2] += 1;
+        assert!(PacketHeader::parse(&damaged, 1).is_none());
+    }
+
+    /// Data without a byte that looks like SOH, so no header is found inside it by chance
+    fn data(len: usize, seed: u8) -> Vec<u8> {
+        (0..len)
+            .map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed))
+            .map(|byte| if byte == Control::Soh.code() { 0 } else { byte })
+            .collect()
+    }
+
+    #[test]
+    fn truncated_packet_gets_erasures() {
+        let packet = Packet::new(data(40, 1), 1).unwrap();
+        let mut symbols = packet.to_binary();
+        let total = symbols.len();
+        symbols.truncate(total - 5);
+
+        let packets = Packet::from_stream(&symbols, &[], VERSION);
+        assert_eq!(packets.len(), 1);
+        // positions count from SOH, the last 5 never arrived
+        assert_eq!(packets[0].erasures, (total - 5..total).collect::<Vec<_>>());
+        let (repaired, _) = packets[0].repair(&*FecScheme::default().codec()).unwrap();
+        assert_eq!(repaired.data, packet.data);
+    }
+
+    #[test]
+    fn corrupted_length_is_rejected_and_the_next_soh_resyncs() {
+        let first = Packet::new(data(40, 1), 1).unwrap();
+        let second = Packet::new(data(30, 2), 2).unwrap();
+        let mut symbols = first.to_binary();
+        // low byte of the length, after SOH, version and flags
+        symbols[4] = Symbol::Data(symbols[4].byte() ^ 0x10);
+        symbols.extend(second.to_binary());
+
+        let packets = Packet::from_stream(&symbols, &[], VERSION);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].header.id, 2);
+        assert_eq!(packets[0].data, second.data);
+        assert!(packets[0].erasures.is_empty());
+    }
+
+    #[test]
+    fn naks_between_packets_are_skipped() {
+        let first = Packet::new(data(40, 1), 1).unwrap();
+        let second = Packet::new(data(30, 2), 2).unwrap();
+        // IDs that spell out a valid packet header, which must not be read as one
+        let mut decoy = Packet::new(data(5, 3), 9).unwrap().header.to_vec();
+        decoy[PacketHeader::chunk_len(VERSION)] = 0;
+        let ids = decoy
+            .chunks(2)
+            .map(|id| (id[0] as u16) << 8 | (id[1] as u16))
+            .collect();
+        let mut symbols = first.to_binary();
+        symbols.extend(Nak::new(ids).to_binary());
+        symbols.extend(second.to_binary());
+        symbols.push(Symbol::Control(Control::Eot));
+
+        let packets = Packet::from_stream(&symbols, &[], VERSION);
+        let ids: Vec<u16> = packets.iter().map(|packet| packet.header.id).collect();
+        assert_eq!(ids, [1, 2]);
+        assert_eq!(packets[1].data, second.data);
+        assert!(packets.iter().all(|packet| packet.erasures.is_empty()));
+    }
 }
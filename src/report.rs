@@ -0,0 +1,222 @@
+//! Statistics of a finished (or failed) transfer as JSON, and the speed table of the README
+//! generated from several of them.
+//!
+//! The JSON is a flat object plus a few arrays of numbers, written by hand and read
+//! with [`crate::json`].
+
+use std::fmt::Write;
+use std::time::Duration;
+
+use crate::{
+    dashboard::PacketMap,
+    json::{self, escape, Json},
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    /// Name of the setup, a column of the speed table, e.g. `Arduino-Arduino`
+    pub label: String,
+    /// Whether all of the peer's data arrived
+    pub completed: bool,
+    /// Payload of the peer that arrived
+    pub bytes_received: usize,
+    /// Our own payload
+    pub bytes_sent: usize,
+    /// Packets of the peer's data
+    pub packets: usize,
+    pub wall_time: Duration,
+    pub nibbles_sent: usize,
+    pub nibbles_received: usize,
+    /// Errors corrected in every packet of the peer, index is the ID - 1
+    pub corrected_errors: Vec<usize>,
+    /// IDs of every copy of a packet that arrived beyond repair
+    pub unrecoverable: Vec<u16>,
+    /// Enquiries and NAKs sent
+    pub enquiry_rounds: usize,
+    pub timeouts: usize,
+}
+
+impl Report {
+    pub fn new(
+        label: &str,
+        packet_map: &PacketMap,
+        wall_time: Duration,
+        bytes_sent: usize,
+        nibbles_sent: usize,
+    ) -> Self {
+        Self {
+            label: label.to_string(),
+            completed: !packet_map.states().is_empty()
+                && packet_map.count(|state| !state.is_received()) == 0,
+            bytes_received: packet_map.payload(),
+            bytes_sent,
+            packets: packet_map.states().len(),
+            wall_time,
+            nibbles_sent,
+            nibbles_received: packet_map.nibbles(),
+            corrected_errors: packet_map.corrected_errors(),
+            unrecoverable: packet_map.unrecoverable().to_vec(),
+            enquiry_rounds: packet_map.requests(),
+            timeouts: packet_map.timeouts(),
+        }
+    }
+
+    fn per_second(&self, count: usize) -> f64 {
+        match self.wall_time.as_secs_f64() {
+            0.0 => 0.0,
+            secs => count as f64 / secs,
+        }
+    }
+
+    /// Payload per second, the larger direction counts as the transfer
+    pub fn payload_rate(&self) -> f64 {
+        self.per_second(self.bytes_received.max(self.bytes_sent))
+    }
+
+    /// Nibbles per second in the busier direction
+    pub fn nibble_rate(&self) -> f64 {
+        self.per_second(self.nibbles_sent.max(self.nibbles_received))
+    }
+
+    pub fn to_json(&self) -> String {
+        let list = |items: &mut dyn Iterator<Item = String>| items.collect::<Vec<_>>().join(", ");
+        let mut json = String::from("{\n");
+        let _ = writeln!(json, "  \"label\": \"{}\",", escape(&self.label));
+        let _ = writeln!(json, "  \"completed\": {},", self.completed);
+        let _ = writeln!(json, "  \"bytes_received\": {},", self.bytes_received);
+        let _ = writeln!(json, "  \"bytes_sent\": {},", self.bytes_sent);
+        let _ = writeln!(json, "  \"packets\": {},", self.packets);
+        let _ = writeln!(
+            json,
+            "  \"wall_time_s\": {:.3},",
+            self.wall_time.as_secs_f64()
+        );
+        let _ = writeln!(
+            json,
+            "  \"payload_bytes_per_s\": {:.3},",
+            self.payload_rate()
+        );
+        let _ = writeln!(json, "  \"nibbles_sent\": {},", self.nibbles_sent);
+        let _ = writeln!(json, "  \"nibbles_received\": {},", self.nibbles_received);
+        let _ = writeln!(json, "  \"nibbles_per_s\": {:.3},", self.nibble_rate());
+        let _ = writeln!(
+            json,
+            "  \"corrected_errors\": [{}],",
+            list(&mut self.corrected_errors.iter().map(usize::to_string))
+        );
+        let _ = writeln!(
+            json,
+            "  \"unrecoverable_packets\": [{}],",
+            list(&mut self.unrecoverable.iter().map(u16::to_string))
+        );
+        let _ = writeln!(json, "  \"enquiry_rounds\": {},", self.enquiry_rounds);
+        let _ = writeln!(json, "  \"timeouts\": {}", self.timeouts);
+        json.push_str("}\n");
+        json
+    }
+
+    /// Reads a report written by [`Report::to_json`], `None` if it is none.
+    /// Derived values like the rates are computed again, not read.
+    pub fn from_json(text: &str) -> Option<Self> {
+        let Json::Object(fields) = json::parse(text)? else {
+            return None;
+        };
+        let field = |name: &str| {
+            fields
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value)
+        };
+        let number = |name: &str| match field(name) {
+            Some(Json::Number(n)) => Some(*n),
+            _ => None,
+        };
+        let numbers = |name: &str| match field(name) {
+            Some(Json::Array(items)) => items
+                .iter()
+                .map(|item| match item {
+                    Json::Number(n) => Some(*n),
+                    _ => None,
+                })
+                .collect::<Option<Vec<f64>>>(),
+            _ => None,
+        };
+        Some(Self {
+            label: match field("label")? {
+                Json::String(label) => label.clone(),
+                _ => return None,
+            },
+            completed: matches!(field("completed"), Some(Json::Bool(true))),
+            bytes_received: number("bytes_received")? as usize,
+            bytes_sent: number("bytes_sent")? as usize,
+            packets: number("packets")? as usize,
+            wall_time: Duration::from_secs_f64(number("wall_time_s")?.max(0.0)),
+            nibbles_sent: number("nibbles_sent")? as usize,
+            nibbles_received: number("nibbles_received")? as usize,
+            corrected_errors: numbers("corrected_errors")?
+                .into_iter()
+                .map(|n| n as usize)
+                .collect(),
+            unrecoverable: numbers("unrecoverable_packets")?
+                .into_iter()
+                .map(|n| n as u16)
+                .collect(),
+            enquiry_rounds: number("enquiry_rounds")? as usize,
+            timeouts: number("timeouts")? as usize,
+        })
+    }
+}
+
+/// The speed table of README section 4.5, a column per report
+pub fn speed_table(reports: &[Report]) -> String {
+    let mut table = String::from("|                           |");
+    for report in reports {
+        let _ = write!(table, " {} |", report.label);
+    }
+    table.push_str("\n|---------------------------|");
+    for _ in reports {
+        table.push_str("---|");
+    }
+    table.push_str("\n| Geschwindigkeit in        |");
+    for report in reports {
+        let _ = write!(
+            table,
+            " {}B/{:.0}s = {:.3}B/s |",
+            report.bytes_received.max(report.bytes_sent),
+            report.wall_time.as_secs_f64(),
+            report.payload_rate()
+        );
+    }
+    table.push_str("\n| Baud in Bits/s            |");
+    for report in reports {
+        let _ = write!(table, " {:.0} * 4 |", report.nibble_rate());
+    }
+    table.push('\n');
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_round_trips() {
+        let report = Report {
+            label: "Nano \"A\" <-> B15".to_string(),
+            completed: true,
+            bytes_received: 1000,
+            bytes_sent: 240,
+            packets: 5,
+            wall_time: Duration::from_millis(12_345),
+            nibbles_sent: 800,
+            nibbles_received: 7000,
+            corrected_errors: vec![0, 3, 0, 0, 1],
+            unrecoverable: vec![2, 2],
+            enquiry_rounds: 1,
+            timeouts: 0,
+        };
+        assert_eq!(Report::from_json(&report.to_json()), Some(report));
+        assert_eq!(Report::from_json("[]"), None);
+        assert_eq!(Report::from_json(r#"{"label": "no numbers"}"#), None);
+    }
+}
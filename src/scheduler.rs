@@ -0,0 +1,122 @@
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{clock::Clock, encoder::SendQueue, error, link, link::Link};
+
+/// Hands out one nibble per `period`, measured against a monotonic clock
+pub struct NibbleScheduler {
+    queue: SendQueue,
+    period: Duration,
+    next_deadline: Option<Instant>,
+    last_sent: Option<Instant>,
+}
+
+impl NibbleScheduler {
+    pub fn new(queue: SendQueue, period: Duration) -> Self {
+        Self {
+            queue,
+            period,
+            next_deadline: None,
+            last_sent: None,
+        }
+    }
+
+    pub fn queue(&self) -> &SendQueue {
+        &self.queue
+    }
+
+    pub fn queue_mut(&mut self) -> &mut SendQueue {
+        &mut self.queue
+    }
+
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    /// When the next nibble is due, `None` while there is nothing to send
+    pub fn next_deadline(&self) -> Option<Instant> {
+        if self.queue.is_empty() {
+            None
+        } else {
+            self.next_deadline
+        }
+    }
+
+    /// Time the last nibble went out
+    pub fn last_sent(&self) -> Option<Instant> {
+        self.last_sent
+    }
+
+    /// Returns the next nibble if its deadline has passed at `now`
+    pub fn poll(&mut self, now: Instant) -> Option<u8> {
+        if self.queue.is_empty() {
+            self.next_deadline = None;
+            return None;
+        }
+        let deadline = *self.next_deadline.get_or_insert(now);
+        if now < deadline {
+            return None;
+        }
+        let nibble = self.queue.pop()?;
+        // keep the average rate, but don't burst to catch up after a long stall
+        self.next_deadline = Some(if now - deadline > self.period {
+            now + self.period
+        } else {
+            deadline + self.period
+        });
+        self.last_sent = Some(now);
+        Some(nibble)
+    }
+}
+
+/// Runs the scheduler on its own thread, `on_sent` is called for every nibble put on the wire
+pub fn spawn_sender(
+    mut link: Box<dyn Link>,
+    scheduler: Arc<Mutex<NibbleScheduler>>,
+    clock: impl Clock + 'static,
+    mut on_sent: impl FnMut(u8) + Send + 'static,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || loop {
+        let (deadline, period) = {
+            let scheduler = scheduler.lock().unwrap();
+            (scheduler.next_deadline(), scheduler.period())
+        };
+        match deadline {
+            Some(deadline) => clock.sleep_until(deadline),
+            // nothing queued: wait in real time, a virtual clock is only moved by its owner
+            None => thread::sleep(period),
+        }
+        let nibble = scheduler.lock().unwrap().poll(clock.now());
+        if let Some(nibble) = nibble {
+            if let Err(e) = link.send(nibble) {
+                error!("Sending failed: {e}");
+                return;
+            }
+            on_sent(nibble);
+        }
+    })
+}
+
+/// Reads the link on its own thread and forwards every nibble into the returned channel.
+/// When nothing arrived it waits `poll_interval` before asking again, a fraction of the
+/// nibble period keeps it from spinning on links that return at once (and from holding
+/// a driver shared with the sender) without missing a clock edge.
+pub fn spawn_receiver(mut link: Box<dyn Link>, poll_interval: Duration) -> mpsc::Receiver<u8> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || loop {
+        match link.receive() {
+            Ok(nibble) => {
+                if tx.send(nibble).is_err() {
+                    return;
+                }
+            }
+            Err(e) if link::is_timeout(&e) => thread::sleep(poll_interval),
+            Err(e) => {
+                error!("Receiving failed: {e}");
+                return;
+            }
+        }
+    });
+    rx
+}
@@ -0,0 +1,303 @@
+//! Wiring self-test: both peers walk patterns across D0–D2 while toggling the clock, each side
+//! checks what arrives on its input lines against what the peer must have sent.
+//!
+//! A round is a clocked nibble sequence: idle clock, every pattern twice with an idle nibble
+//! after it, idle clock again; rounds are separated by a pause with the clock standing still.
+//! A round that arrived is lined up from its end, so the pause finds it whatever the state of
+//! the data lines. Every input line is compared against what it should carry, the peer's
+//! other lines, a short of two lines and a constant level; the closest one is the diagnosis.
+//! Missing or extra clock edges show up as a round of the wrong length.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use ansi_term::Color::{Green, Red, Yellow};
+
+use crate::encoder::wire_nibbles;
+
+/// Each data line alone, alternating, all ones, then each line alone low
+pub const PATTERNS: [u8; 9] = [
+    0b001, 0b010, 0b100, 0b101, 0b010, 0b111, 0b110, 0b101, 0b011,
+];
+const LEAD_IN: usize = 8;
+/// Makes the round an even number of edges, the clock is low after it and the next round
+/// starts with an edge
+const LEAD_OUT: usize = 5;
+/// Clock standing still between rounds, longer than any gap within one
+pub const PAUSE: Duration = Duration::from_millis(500);
+/// A line whose best explanation still misses this share of the round is called noisy
+const NOISY: f64 = 0.1;
+
+/// Data of the clocked nibbles of one round
+pub fn round() -> Vec<u8> {
+    let mut data = vec![0; LEAD_IN];
+    for pattern in PATTERNS {
+        data.extend([pattern, pattern, 0]);
+    }
+    data.extend([0; LEAD_OUT]);
+    data
+}
+
+/// Wire nibbles of one round, data first and then the clock edge like every transmission
+pub fn round_wire_nibbles() -> Vec<u8> {
+    round()
+        .into_iter()
+        .enumerate()
+        .flat_map(|(i, data)| wire_nibbles(((i as u8 & 1) ^ 1) << 3 | data))
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineFault {
+    /// Always reads this level, e.g. 1 for a loose jumper thanks to the pull-ups
+    StuckAt(u8),
+    /// Carries the peer's other line, the jumpers are swapped
+    Swapped(usize),
+    /// Follows this line as well, shorted or crosstalk
+    Crosstalk(usize),
+    /// No simple explanation, number of wrong samples
+    Noisy(usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnosis {
+    /// Clock edges of the round, `received` differs if the clock line is unreliable
+    pub expected_edges: usize,
+    pub received_edges: usize,
+    /// Fault of D0, D1 and D2, `None` if the line is fine
+    pub lines: [Option<LineFault>; 3],
+}
+
+impl Diagnosis {
+    pub fn is_ok(&self) -> bool {
+        self.expected_edges == self.received_edges && self.lines.iter().all(Option::is_none)
+    }
+}
+
+/// Diagnoses one round as it arrived, the clocked nibbles the Arduino reported
+pub fn diagnose(received: &[u8]) -> Diagnosis {
+    let expected = round();
+    // lined up from the end, a clock fault shifts the beginning
+    let pairs: Vec<(u8, u8)> = expected
+        .iter()
+        .rev()
+        .zip(received.iter().rev())
+        .map(|(&expected, &received)| (expected, received & 0b111))
+        .collect();
+    let line = |value: u8, line: usize| value >> line & 1;
+    let misses = |target: usize, source: &dyn Fn(u8) -> u8| {
+        pairs
+            .iter()
+            .filter(|&&(expected, received)| source(expected) != line(received, target))
+            .count()
+    };
+
+    let mut lines = [None; 3];
+    for (target, fault) in lines.iter_mut().enumerate() {
+        let errors = misses(target, &|expected| line(expected, target));
+        if errors == 0 {
+            continue;
+        }
+        // best explanation first, ties go to the simpler fault
+        let mut candidates: Vec<(usize, LineFault)> = Vec::new();
+        for level in 0..2 {
+            candidates.push((misses(target, &|_| level), LineFault::StuckAt(level)));
+        }
+        for other in (0..3).filter(|&other| other != target) {
+            candidates.push((
+                misses(target, &|expected| line(expected, other)),
+                LineFault::Swapped(other),
+            ));
+            let or = misses(target, &|expected| {
+                line(expected, target) | line(expected, other)
+            });
+            let and = misses(target, &|expected| {
+                line(expected, target) & line(expected, other)
+            });
+            candidates.push((or.min(and), LineFault::Crosstalk(other)));
+        }
+        let (misses, best) = candidates
+            .into_iter()
+            .min_by_key(|&(misses, _)| misses)
+            .unwrap();
+        *fault = Some(if misses as f64 > pairs.len() as f64 * NOISY {
+            LineFault::Noisy(errors)
+        } else {
+            best
+        });
+    }
+
+    Diagnosis {
+        expected_edges: expected.len(),
+        received_edges: received.len(),
+        lines,
+    }
+}
+
+/// Collects the peer's rounds, a round is over once the clock stood still for half a [`PAUSE`]
+#[derive(Default)]
+pub struct SelfTest {
+    nibbles: Vec<u8>,
+    last: Option<Instant>,
+}
+
+impl SelfTest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn on_nibble(&mut self, nibble: u8, now: Instant) {
+        self.nibbles.push(nibble);
+        self.last = Some(now);
+    }
+
+    /// The diagnosis of a round once it is over
+    pub fn poll(&mut self, now: Instant) -> Option<Diagnosis> {
+        let last = self.last?;
+        if now.saturating_duration_since(last) < PAUSE / 2 {
+            return None;
+        }
+        self.last = None;
+        Some(diagnose(&std::mem::take(&mut self.nibbles)))
+    }
+}
+
+const LINES: [&str; 3] = ["D0", "D1", "D2"];
+
+impl fmt::Display for Diagnosis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.received_edges == self.expected_edges {
+            write!(f, "CLK  {}", Green.paint("ok"))?;
+        } else {
+            write!(
+                f,
+                "CLK  {}",
+                Red.paint(format!(
+                    "{} of {} edges, loose or crosstalk on the clock",
+                    self.received_edges, self.expected_edges
+                ))
+            )?;
+        }
+        for (name, fault) in LINES.iter().zip(&self.lines) {
+            write!(f, "\n{name:<4} ")?;
+            match fault {
+                None => write!(f, "{}", Green.paint("ok"))?,
+                Some(LineFault::StuckAt(level)) => write!(
+                    f,
+                    "{}",
+                    Red.paint(format!("stuck at {level}, not connected?"))
+                )?,
+                Some(LineFault::Swapped(other)) => write!(
+                    f,
+                    "{}",
+                    Red.paint(format!("carries {}, swapped", LINES[*other]))
+                )?,
+                Some(LineFault::Crosstalk(other)) => write!(
+                    f,
+                    "{}",
+                    Yellow.paint(format!("follows {} too, shorted", LINES[*other]))
+                )?,
+                Some(LineFault::Noisy(errors)) => write!(
+                    f,
+                    "{}",
+                    Yellow.paint(format!("{errors} wrong samples, noisy"))
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The round as it arrives when `wiring` maps the peer's data to our lines
+    fn arrived(wiring: impl Fn(u8) -> u8) -> Vec<u8> {
+        round()
+            .into_iter()
+            .enumerate()
+            .map(|(i, data)| ((i as u8 & 1) ^ 1) << 3 | wiring(data) & 0b111)
+            .collect()
+    }
+
+    fn line(data: u8, line: usize) -> u8 {
+        data >> line & 1
+    }
+
+    fn faults(lines: [Option<LineFault>; 3]) -> Diagnosis {
+        let edges = round().len();
+        Diagnosis {
+            expected_edges: edges,
+            received_edges: edges,
+            lines,
+        }
+    }
+
+    #[test]
+    fn intact_wiring() {
+        assert!(diagnose(&arrived(|data| data)).is_ok());
+    }
+
+    #[test]
+    fn stuck_line() {
+        let diagnosis = diagnose(&arrived(|data| data | 0b010));
+        assert_eq!(diagnosis, faults([None, Some(LineFault::StuckAt(1)), None]));
+        let diagnosis = diagnose(&arrived(|data| data & 0b011));
+        assert_eq!(diagnosis, faults([None, None, Some(LineFault::StuckAt(0))]));
+    }
+
+    #[test]
+    fn swapped_lines() {
+        let diagnosis = diagnose(&arrived(|data| {
+            line(data, 2) | line(data, 1) << 1 | line(data, 0) << 2
+        }));
+        assert_eq!(
+            diagnosis,
+            faults([
+                Some(LineFault::Swapped(2)),
+                None,
+                Some(LineFault::Swapped(0))
+            ])
+        );
+    }
+
+    #[test]
+    fn crosstalk() {
+        let diagnosis = diagnose(&arrived(|data| data | line(data, 0) << 1));
+        assert_eq!(
+            diagnosis,
+            faults([None, Some(LineFault::Crosstalk(0)), None])
+        );
+    }
+
+    #[test]
+    fn missing_and_extra_clock_edges() {
+        // the round is lined up from its end, the data lines are still judged right
+        let mut received = arrived(|data| data);
+        received.remove(0);
+        let diagnosis = diagnose(&received);
+        assert_eq!(diagnosis.received_edges, diagnosis.expected_edges - 1);
+        assert_eq!(diagnosis.lines, [None; 3]);
+        assert!(!diagnosis.is_ok());
+
+        received.splice(0..0, [0b1000, 0, 0b1000]);
+        let diagnosis = diagnose(&received);
+        assert_eq!(diagnosis.received_edges, diagnosis.expected_edges + 2);
+        assert_eq!(diagnosis.lines, [None; 3]);
+    }
+
+    #[test]
+    fn round_ends_after_half_a_pause() {
+        let start = Instant::now();
+        let mut test = SelfTest::new();
+        for (i, nibble) in arrived(|data| data).into_iter().enumerate() {
+            test.on_nibble(nibble, start + Duration::from_millis(i as u64));
+            assert_eq!(test.poll(start + Duration::from_millis(i as u64)), None);
+        }
+        let last = start + Duration::from_millis(round().len() as u64 - 1);
+        assert_eq!(test.poll(last + PAUSE / 4), None);
+        assert!(test.poll(last + PAUSE / 2).unwrap().is_ok());
+        assert_eq!(test.poll(last + PAUSE), None);
+    }
+}
@@ -0,0 +1,859 @@
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+use ansi_term::Color::Yellow;
+
+use crate::{
+    arq::{ArqConfig, RetransmitTimer, RetriesExhausted},
+    dashboard::{PacketMap, PacketState},
+    debug,
+    encoder::{Frame, Outgoing},
+    enquiry::{decode_ids, encode_ids},
+    error,
+    fec::FecScheme,
+    info,
+    monitor::{Notice, StreamMonitor},
+    protocol::{Message, Nak, ProtocolDecoder, State, Transmission, NAK_MAX_IDS},
+    trace,
+    utilities::{is_sot, make_transmission, squash_nibbles, start_and_end},
+    warn,
+};
+
+/// Progress of our own data towards the peer
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Outbound {
+    /// Sent (or being sent), no ack yet
+    AwaitingAck,
+    Acked,
+}
+
+/// Outcome of a fully received transmission
+#[derive(Debug)]
+pub enum Event {
+    /// A transmission was evaluated, `missing` packets of the peer's data are still outstanding
+    Evaluated { missing: usize },
+    /// Every packet of the peer's data arrived, only reported once
+    Completed(Vec<u8>),
+    /// The peer acknowledged our data
+    Acked,
+    /// The peer announced itself
+    Hello,
+    /// The peer gave up the transfer
+    Aborted,
+    /// Information the peer sent about its data
+    Metadata(Vec<u8>),
+}
+
+/// Nibble gaps without a nibble after which the peer's frame is taken to be over
+const QUIET_NIBBLES: u32 = 16;
+
+/// Receive state machine, enquiry retransmission and ack tracking of one peer.
+/// It is driven by received nibbles and timestamps from a [`crate::clock::Clock`]
+/// and hands frames to send back to the caller, so it runs the same on real hardware and in a simulation.
+pub struct Session {
+    state: State,
+    transmission: Transmission,
+    transmission_packet_array: Vec<Vec<u8>>,
+    broken_ids: Vec<u16>,
+    received: Vec<u8>,
+    monitor: StreamMonitor,
+    chunk_size: usize,
+    rto: RetransmitTimer,
+    outbound: Outbound,
+    ack_rto: RetransmitTimer,
+    /// The timeouts are held while a frame of the peer arrives
+    holding: bool,
+    last_nibble: Option<Instant>,
+    /// Average time between the nibbles of a frame
+    nibble_gap: Duration,
+    completed: bool,
+    packets: PacketMap,
+}
+
+impl Session {
+    /// `transmission` is our own data, used to answer enquiries of the peer
+    pub fn new(transmission: Transmission, chunk_size: usize, arq: ArqConfig) -> Self {
+        Self {
+            state: State::Normal,
+            transmission,
+            transmission_packet_array: (0..=u16::MAX).map(|_| Vec::new()).collect(),
+            broken_ids: Vec::new(),
+            received: Vec::new(),
+            monitor: StreamMonitor::new(),
+            chunk_size,
+            rto: RetransmitTimer::new(arq),
+            outbound: Outbound::AwaitingAck,
+            ack_rto: RetransmitTimer::new(arq),
+            holding: false,
+            last_nibble: None,
+            nibble_gap: Duration::ZERO,
+            completed: false,
+            packets: PacketMap::new(),
+        }
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    pub fn outbound(&self) -> Outbound {
+        self.outbound
+    }
+
+    /// Peer's data complete and ours acknowledged
+    pub fn is_finished(&self) -> bool {
+        self.completed && self.outbound == Outbound::Acked
+    }
+
+    pub fn retransmit_timer(&self) -> &RetransmitTimer {
+        &self.rto
+    }
+
+    /// IDs of the peer's packets that are still missing or broken
+    pub fn broken_ids(&self) -> &[u16] {
+        &self.broken_ids
+    }
+
+    /// State of every packet of the peer's data
+    pub fn packet_map(&self) -> &PacketMap {
+        &self.packets
+    }
+
+    /// Feeds one received nibble, whatever is to be sent is appended to `outgoing`
+    pub fn on_nibble(
+        &mut self,
+        nibble: u8,
+        now: Instant,
+        outgoing: &mut Vec<Outgoing>,
+    ) -> Option<Event> {
+        self.packets.on_nibble(now);
+        if let Some(notice) = self.monitor.push(nibble) {
+            self.on_notice(notice, outgoing);
+        }
+        // the peer can't answer while it sends us a frame. Lost nibbles may fool the monitor
+        // into seeing its end early, so the hold lasts until a frame was decoded or the
+        // nibbles stopped coming.
+        let gap = self
+            .last_nibble
+            .map(|last| now.saturating_duration_since(last));
+        self.last_nibble = Some(now);
+        if self.monitor.in_frame() && !self.holding {
+            self.holding = true;
+            self.nibble_gap = gap.unwrap_or_default();
+            self.rto.pause(now);
+            self.ack_rto.pause(now);
+        } else if let (true, Some(gap)) = (self.holding, gap) {
+            self.nibble_gap = (self.nibble_gap * 7 + gap) / 8;
+        }
+        self.received.push(nibble);
+        if let Some(back) = self.monitor.frame_started() {
+            // nothing before the SOT belongs to a frame anymore
+            self.received
+                .drain(..self.received.len().saturating_sub(back));
+        }
+        if self.received.len() < 6 {
+            return None;
+        }
+        // the monitor knows the frame still arrives
+        if self.monitor.is_following() {
+            return None;
+        }
+        let transmission = loop {
+            if self.monitor.frame_ended() {
+                // junk before the frame may look like an SOT, the frame's own is in line with
+                // the EOT just received
+                let eot = self.received.len() - 3;
+                let sot = (eot % 3..eot)
+                    .step_by(3)
+                    .find(|&i| is_sot(&self.received[i..]))?;
+                self.received.drain(..sot);
+            }
+            let (start, end) = start_and_end(&self.received)?;
+            if let Some(transmission) = decode(&self.received[start..end + 3]) {
+                self.received.drain(..end + 3);
+                break transmission;
+            }
+            // the EOT may be misaligned data of a frame that lost nibbles, or the SOT junk:
+            // only the SOT is dropped, a later frame in the buffer is kept
+            self.received.drain(..=start);
+        };
+        // the EOT was real, whether or not the monitor kept up with the frame
+        self.release(now);
+        match transmission.header.message {
+            Message::Data | Message::Enquiry | Message::Nak | Message::Ack => {}
+            Message::Hello => {
+                info!("Peer says hello");
+                return Some(Event::Hello);
+            }
+            Message::Abort => {
+                error!("Peer aborted the transfer");
+                return Some(Event::Aborted);
+            }
+            Message::Metadata => return Some(Event::Metadata(payload(transmission))),
+            Message::Unknown(byte) => {
+                debug!("Skipping message of unknown type {byte}");
+                return None;
+            }
+        }
+        let outbound = self.outbound;
+        let message = transmission.header.message;
+        // INFO: this returns ids of packets that are not recoverable or missing
+        let (broken_ids, completed) = self.auswertung(transmission, now, outgoing)?;
+        self.broken_ids = broken_ids;
+        if message == Message::Data && self.broken_ids.is_empty() {
+            self.rto.cancel();
+        }
+
+        if !self.broken_ids.is_empty() {
+            info!("Need {} packets to be resent!", self.broken_ids.len());
+            self.packets.on_requested(&self.broken_ids);
+            if self.rto.is_armed() {
+                self.rto.on_retransmit();
+            }
+            outgoing.extend(self.enquiry());
+            self.state = State::WaitingForResponse;
+        }
+
+        if let Some(data) = completed {
+            // ack every time, the previous ack might have been lost
+            outgoing.push(Outgoing::Frame(Frame::from_transmission(
+                Transmission::ack(),
+            )));
+            if !self.completed {
+                self.completed = true;
+                return Some(Event::Completed(data));
+            }
+        }
+
+        if outbound != self.outbound {
+            return Some(Event::Acked);
+        }
+        Some(Event::Evaluated {
+            missing: self.broken_ids.len(),
+        })
+    }
+
+    /// Tells the session a nibble went out at `at`, `drained` if nothing else is queued.
+    /// The enquiry timeout and the ack timeout start once everything has been sent.
+    pub fn on_sent(&mut self, at: Instant, drained: bool) {
+        if !drained {
+            return;
+        }
+        if self.state == State::WaitingForResponse && !self.rto.is_armed() {
+            self.rto.arm(at);
+        }
+        if self.outbound == Outbound::AwaitingAck && !self.ack_rto.is_armed() {
+            self.ack_rto.arm(at);
+        }
+    }
+
+    /// The peer's frame was through at `at`, the timeouts run again
+    fn release(&mut self, at: Instant) {
+        self.holding = false;
+        self.rto.resume(at);
+        self.ack_rto.resume(at);
+    }
+
+    /// Checks the timeouts, returns `true` if an enquiry or probe was sent
+    /// and an error once the peer did not answer `max_retries` times in a row
+    pub fn poll(
+        &mut self,
+        now: Instant,
+        outgoing: &mut Vec<Outgoing>,
+    ) -> Result<bool, RetriesExhausted> {
+        // no frame was decoded, but the peer went quiet for many nibbles
+        if let (true, Some(last)) = (self.holding, self.last_nibble) {
+            if now.saturating_duration_since(last) > self.nibble_gap * QUIET_NIBBLES {
+                self.release(last);
+            }
+        }
+        let mut resent = false;
+        if self.rto.expired(now) {
+            self.rto.on_timeout()?;
+            self.packets.on_timeout();
+            warn!(
+                "Timeout: resending Enquiry! (retry {}, next timeout {:?})",
+                self.rto.retries(),
+                self.rto.rto()
+            );
+            // a frame of the peer may be arriving
+            if !self.monitor.in_frame() {
+                self.received.clear();
+            }
+            self.packets.on_requested(&self.broken_ids);
+            outgoing.extend(self.enquiry());
+            resent = true;
+        }
+        if self.ack_rto.expired(now) {
+            self.ack_rto.on_timeout()?;
+            self.packets.on_timeout();
+            warn!(
+                "Timeout: no answer to our data, probing! (retry {}, next timeout {:?})",
+                self.ack_rto.retries(),
+                self.ack_rto.rto()
+            );
+            let total_packets = self.transmission.header.total_packets;
+            outgoing.push(Outgoing::Frame(Frame::from_transmission(
+                Transmission::probe(total_packets),
+            )));
+            resent = true;
+        }
+        Ok(resent)
+    }
+
+    /// `None` if our chunk size is too long for a packet, the enquiry then times out
+    fn enquiry(&self) -> Option<Outgoing> {
+        let chunked = encode_ids(&self.broken_ids, self.chunk_size);
+        let Some(packets) = make_transmission(chunked, &*FecScheme::default().codec()) else {
+            error!(
+                "Enquiry chunks of {} bytes don't fit a packet",
+                self.chunk_size
+            );
+            return None;
+        };
+        Some(Outgoing::Frame(Frame::from_transmission(
+            Transmission::new(packets, Message::Enquiry),
+        )))
+    }
+
+    /// Our packets with the given IDs, under our transmission header
+    fn resend(&mut self, ids: &HashSet<u16>) -> Outgoing {
+        let mut transmission = self.transmission.clone();
+        transmission
+            .packets
+            .retain(|packet| ids.contains(&packet.header.id));
+        self.packets.on_resend(transmission.packets.len());
+        Outgoing::Resend(transmission)
+    }
+
+    /// Reacts to what the monitor noticed before the transmission is complete
+    fn on_notice(&mut self, notice: Notice, outgoing: &mut Vec<Outgoing>) {
+        match notice {
+            Notice::Gap(ids) => {
+                let ids: Vec<u16> = ids
+                    .into_iter()
+                    .filter(|&id| {
+                        self.transmission_packet_array
+                            .get(id as usize)
+                            .is_some_and(Vec::is_empty)
+                    })
+                    .collect();
+                if ids.is_empty() || self.completed {
+                    return;
+                }
+                info!("Packets {ids:?} missing, sending NAK");
+                self.packets.on_requested(&ids);
+                for chunk in ids.chunks(NAK_MAX_IDS) {
+                    outgoing.push(Outgoing::Control(Nak::new(chunk.to_vec()).to_binary()));
+                }
+            }
+            Notice::Nak(ids) => {
+                info!("Peer NAKed packets {ids:?}, resending");
+                let resend = self.resend(&ids.into_iter().collect());
+                outgoing.push(resend);
+            }
+        }
+    }
+
+    /// Returns the IDs still missing and, once everything arrived, the peer's data.
+    /// `None` if the transmission had to be dropped.
+    fn auswertung(
+        &mut self,
+        transmission: Transmission,
+        now: Instant,
+        outgoing: &mut Vec<Outgoing>,
+    ) -> Option<(Vec<u16>, Option<Vec<u8>>)> {
+        let fec = transmission.header.fec.codec();
+        match transmission.header.message {
+            Message::Ack => {
+                if self.outbound != Outbound::Acked {
+                    info!("Peer acknowledged our data");
+                }
+                self.outbound = Outbound::Acked;
+                self.ack_rto.on_response(now);
+            }
+            Message::Enquiry | Message::Nak => {
+                // the peer got at least our header, wait for the ack again after answering
+                self.ack_rto.on_response(now);
+                let mut ids: HashSet<u16> = HashSet::new();
+                for packet in transmission.packets {
+                    match packet.repair(&*fec) {
+                        Some((packet, errors)) => {
+                            if errors > 0 {
+                                debug!(
+                                    "Repaired Packet {}, had {} errors!",
+                                    packet.header.id, errors
+                                );
+                            }
+                            match decode_ids(&packet.data) {
+                                Some(local_ids) => ids.extend(local_ids),
+                                None => warn!("Malformed enquiry packet {}", packet.header.id),
+                            }
+                        }
+                        None => {
+                            let id = packet.header.id;
+                            warn!("Packet {id} unrecoverable");
+                            trace!("{packet:?}");
+                        }
+                    }
+                }
+                // respond with data for requested packets
+                let resend = self.resend(&ids);
+                outgoing.push(resend);
+                info!("Responding to {:?}...", transmission.header.message);
+            }
+            Message::Data => {
+                // the answer to our enquiry carries packets we asked for
+                let answers_enquiry = self.state == State::WaitingForResponse
+                    && transmission
+                        .packets
+                        .iter()
+                        .any(|packet| self.broken_ids.contains(&packet.header.id));
+                if answers_enquiry {
+                    self.rto.on_response(now);
+                }
+                self.state = State::Normal;
+                self.packets.set_total(transmission.header.total_packets);
+                // parity packets are numbered on from the data packets
+                let total_packets = transmission.header.total_packets;
+                let last_id = total_packets as usize
+                    + transmission.header.outer_code.map_or(0, |code| {
+                        code.groups(total_packets) as usize * code.parity as usize
+                    });
+                for packet in transmission.packets {
+                    if packet.header.id == 0 || packet.header.id as usize > last_id {
+                        warn!("Ignoring packet {} of {total_packets}", packet.header.id);
+                        continue;
+                    }
+                    let bytes = packet.data.len() + packet.ecc.len();
+                    match packet.repair(&*fec) {
+                        Some((packet, errors)) => {
+                            let state = match errors {
+                                0 => PacketState::Clean,
+                                errors => PacketState::Repaired(errors),
+                            };
+                            self.packets.on_packet(
+                                packet.header.id,
+                                state,
+                                bytes,
+                                packet.data.len(),
+                            );
+                            if errors > 0 {
+                                debug!("Packet {} had {} errors!", packet.header.id, errors);
+                            }
+                            trace!(
+                                "{} ({}/{})",
+                                Yellow.paint("Packet OK"),
+                                packet.header.id,
+                                transmission.header.total_packets
+                            );
+                            self.transmission_packet_array[packet.header.id as usize] = packet.data;
+                        }
+                        None => {
+                            let id = packet.header.id;
+                            self.packets
+                                .on_packet(id, PacketState::Unrecoverable, bytes, 0);
+                            warn!("Packet {id} unrecoverable");
+                            trace!("{packet:?}");
+                        }
+                    }
+                }
+                if let Some(outer_code) = transmission
+                    .header
+                    .outer_code
+                    .filter(|_| last_id <= u16::MAX as usize)
+                {
+                    let rebuilt =
+                        outer_code.rebuild(total_packets, &mut self.transmission_packet_array);
+                    if !rebuilt.is_empty() {
+                        info!("Rebuilt packets {rebuilt:?} from parity");
+                    }
+                    for id in rebuilt {
+                        let data = self.transmission_packet_array[id as usize].len();
+                        self.packets.on_rebuilt(id, data);
+                    }
+                }
+            }
+            // handled by the caller, nothing to evaluate
+            Message::Hello | Message::Abort | Message::Metadata | Message::Unknown(_) => {
+                return None
+            }
+        }
+        let mut unrepairable_packets: Vec<u16> = Vec::new();
+        let total_packets = transmission.header.total_packets;
+        for packet_id in 1..=total_packets {
+            // 1 - da es kein packet mit id 0 gibt
+            if self.transmission_packet_array[packet_id as usize] == Vec::new() {
+                unrepairable_packets.push(packet_id);
+            }
+        }
+
+        let mut completed = None;
+        if unrepairable_packets.is_empty() && transmission.header.message == Message::Data {
+            // parity packets are stored behind the data packets
+            completed = Some(self.transmission_packet_array[..=total_packets as usize].concat());
+        }
+
+        Some((unrepairable_packets, completed))
+    }
+}
+
+/// Squashes the received nibbles from SOT up to and including EOT into bytes and decodes them
+fn decode(frame: &[u8]) -> Option<Transmission> {
+    let mut p = ProtocolDecoder::new(squash_nibbles(frame));
+    p.decode()
+}
+
+/// Data of all packets that could be repaired, in the order of their ids
+fn payload(transmission: Transmission) -> Vec<u8> {
+    let fec = transmission.header.fec.codec();
+    let mut packets: Vec<_> = transmission
+        .packets
+        .into_iter()
+        .filter_map(|packet| match packet.repair(&*fec) {
+            Some((packet, _)) => Some(packet),
+            None => {
+                warn!("Packet {} unrecoverable", packet.header.id);
+                None
+            }
+        })
+        .collect();
+    packets.sort_by_key(|packet| packet.header.id);
+    packets.into_iter().flat_map(|packet| packet.data).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::clock::{Clock, VirtualClock};
+    use crate::encoder::SendQueue;
+    use crate::link::{loopback, Link};
+    use crate::protocol::{Packet, TransmissionHeader};
+    use crate::scheduler::NibbleScheduler;
+    use crate::utilities::chunk_data;
+
+    const PERIOD: Duration = Duration::from_millis(4);
+
+    fn arq() -> ArqConfig {
+        ArqConfig {
+            initial_rto: Duration::from_millis(500),
+            min_rto: Duration::from_millis(100),
+            max_rto: Duration::from_secs(10),
+            max_retries: 3,
+        }
+    }
+
+    /// One side of a simulated transfer, stepped on virtual time instead of threads
+    struct Peer {
+        session: Session,
+        scheduler: NibbleScheduler,
+        link: Box<dyn Link>,
+        events: Vec<Event>,
+        frames_sent: usize,
+        nibbles_sent: usize,
+        timeouts: usize,
+    }
+
+    impl Peer {
+        fn new(data: &[u8], link: impl Link + 'static) -> Self {
+            let transmission = Transmission::from_chunks(
+                chunk_data(data.to_vec(), 16),
+                FecScheme::default(),
+                None,
+            )
+            .unwrap();
+            let mut queue = SendQueue::with_preamble(8);
+            queue.push(Frame::from_transmission(transmission.clone()));
+            Self {
+                session: Session::new(transmission, 16, arq()),
+                scheduler: NibbleScheduler::new(queue, PERIOD),
+                link: Box::new(link),
+                events: Vec::new(),
+                frames_sent: 1,
+                nibbles_sent: 0,
+                timeouts: 0,
+            }
+        }
+
+        /// Sends what is due, feeds what arrived and checks the timeouts, like the main loop
+        fn step(&mut self, now: Instant) -> Result<(), RetriesExhausted> {
+            if let Some(nibble) = self.scheduler.poll(now) {
+                self.link.send(nibble).unwrap();
+                self.nibbles_sent += 1;
+            }
+            if let Some(sent) = self.scheduler.last_sent() {
+                let drained = self.scheduler.queue().is_empty();
+                self.session.on_sent(sent, drained);
+            }
+            let mut outgoing = Vec::new();
+            while let Ok(nibble) = self.link.receive() {
+                if let Some(event) = self.session.on_nibble(nibble, now, &mut outgoing) {
+                    self.events.push(event);
+                }
+            }
+            self.timeouts += self.session.poll(now, &mut outgoing)? as usize;
+            for out in outgoing {
+                self.frames_sent += matches!(out, Outgoing::Frame(_)) as usize;
+                self.scheduler.queue_mut().submit(out);
+            }
+            Ok(())
+        }
+
+        fn completed(&self) -> Option<&[u8]> {
+            self.events.iter().find_map(|event| match event {
+                Event::Completed(data) => Some(data.as_slice()),
+                _ => None,
+            })
+        }
+    }
+
+    #[test]
+    fn transfer_completes_over_loopback() {
+        let clock = VirtualClock::new();
+        let (a, b) = loopback();
+        let mut alice = Peer::new(b"Hello from Alice, over a simulated wire", a);
+        let mut bob = Peer::new(b"and Bob answers", b);
+
+        for _ in 0..20_000 {
+            alice.step(clock.now()).unwrap();
+            bob.step(clock.now()).unwrap();
+            if alice.session.is_finished() && bob.session.is_finished() {
+                break;
+            }
+            clock.advance(PERIOD);
+        }
+        assert_eq!(alice.completed(), Some(&b"and Bob answers"[..]));
+        assert_eq!(
+            bob.completed(),
+            Some(&b"Hello from Alice, over a simulated wire"[..])
+        );
+        assert!(alice.session.is_finished() && bob.session.is_finished());
+        assert_eq!(alice.session.retransmit_timer().retries(), 0);
+        // Bob waited for the ack while Alice's longer data was still arriving: that time is
+        // held out, the round trip is the gap between her EOT and her ack
+        let round_trip = bob.session.ack_rto.srtt().unwrap();
+        assert!(round_trip > PERIOD, "{round_trip:?}");
+        assert!(round_trip < Duration::from_millis(500), "{round_trip:?}");
+        assert_eq!(bob.session.ack_rto.retries(), 0);
+    }
+
+    /// Drops the wire nibbles sent at the indices in `lost`, like clock edges the receiver missed
+    struct LossyLink {
+        link: Box<dyn Link>,
+        sent: usize,
+        lost: std::ops::Range<usize>,
+    }
+
+    impl Link for LossyLink {
+        fn send(&mut self, nibble: u8) -> std::io::Result<()> {
+            self.sent += 1;
+            if self.lost.contains(&(self.sent - 1)) {
+                return Ok(());
+            }
+            self.link.send(nibble)
+        }
+
+        fn receive(&mut self) -> std::io::Result<u8> {
+            self.link.receive()
+        }
+
+        fn try_clone(&self) -> std::io::Result<Box<dyn Link>> {
+            unimplemented!()
+        }
+    }
+
+    #[test]
+    fn transfer_survives_lost_clock_edges() {
+        let data: Vec<u8> = (0..600u32).map(|i| (i * 7 % 251) as u8).collect();
+        for count in 1..=6 {
+            let clock = VirtualClock::new();
+            let (a, b) = loopback();
+            // well into Alice's data frame
+            let lossy = LossyLink {
+                link: Box::new(a),
+                sent: 0,
+                lost: 6000..6000 + count,
+            };
+            let mut alice = Peer::new(&data, lossy);
+            let mut bob = Peer::new(b"and Bob answers", b);
+            for _ in 0..200_000 {
+                alice.step(clock.now()).unwrap();
+                bob.step(clock.now()).unwrap();
+                if alice.session.is_finished() && bob.session.is_finished() {
+                    break;
+                }
+                clock.advance(PERIOD);
+            }
+            assert_eq!(bob.completed(), Some(&data[..]), "{count} lost");
+            assert!(alice.session.is_finished(), "{count} lost");
+            // both waited while the other's frames arrived, the broken one too
+            assert_eq!((alice.timeouts, bob.timeouts), (0, 0), "{count} lost");
+        }
+    }
+
+    /// Data transmission announcing `total_packets`, carrying one packet with `id`
+    fn single_packet(total_packets: u16, id: u16) -> Transmission {
+        let fec = FecScheme::default();
+        Transmission {
+            header: TransmissionHeader::data(total_packets, fec, None),
+            packets: vec![Packet::with_fec(b"last".to_vec(), id, &*fec.codec()).unwrap()],
+        }
+    }
+
+    #[test]
+    fn packet_ids_up_to_u16_max_are_evaluated() {
+        let mut session = Session::new(Transmission::ack(), 16, arq());
+        let transmission = single_packet(u16::MAX, u16::MAX);
+        let (missing, completed) = session
+            .auswertung(transmission, Instant::now(), &mut Vec::new())
+            .unwrap();
+        assert_eq!(missing.len(), u16::MAX as usize - 1);
+        assert_eq!(missing.last(), Some(&(u16::MAX - 1)));
+        assert_eq!(completed, None);
+    }
+
+    #[test]
+    fn packets_beyond_the_total_are_ignored() {
+        let mut session = Session::new(Transmission::ack(), 16, arq());
+        for id in [0, 2, u16::MAX] {
+            let (missing, completed) = session
+                .auswertung(single_packet(1, id), Instant::now(), &mut Vec::new())
+                .unwrap();
+            assert_eq!((missing, completed), (vec![1], None), "{id}");
+        }
+        let (missing, completed) = session
+            .auswertung(single_packet(1, 1), Instant::now(), &mut Vec::new())
+            .unwrap();
+        assert!(missing.is_empty());
+        assert_eq!(completed.as_deref(), Some(&b"last"[..]));
+    }
+
+    #[test]
+    fn unknown_message_is_skipped_up_to_its_eot() {
+        let (mut a, b) = loopback();
+        let mut bob = Peer::new(b"Bob's data", b);
+        let newer = make_transmission(
+            chunk_data(b"what a newer peer sends".to_vec(), 16),
+            &*FecScheme::default().codec(),
+        )
+        .unwrap();
+        let data = b"Alice's data behind it";
+        let mut queue = SendQueue::with_preamble(8);
+        queue.push(Frame::from_transmission(Transmission::new(
+            newer,
+            Message::Unknown(9),
+        )));
+        queue.push(Frame::from_transmission(
+            Transmission::from_chunks(chunk_data(data.to_vec(), 16), FecScheme::default(), None)
+                .unwrap(),
+        ));
+        for nibble in std::iter::from_fn(|| queue.pop()) {
+            a.send(nibble).unwrap();
+        }
+
+        bob.step(Instant::now()).unwrap();
+        assert_eq!(bob.completed(), Some(&data[..]));
+        assert_eq!(bob.events.len(), 1, "{:?}", bob.events);
+    }
+
+    #[test]
+    fn frame_after_one_that_lost_nibbles_is_decoded() {
+        let (mut a, b) = loopback();
+        let mut bob = Peer::new(b"Bob's data", b);
+        let data = b"Alice's data, sent again";
+        let transmission =
+            Transmission::from_chunks(chunk_data(data.to_vec(), 16), FecScheme::default(), None)
+                .unwrap();
+        let mut queue = SendQueue::with_preamble(8);
+        queue.push(Frame::from_transmission(transmission.clone()));
+        let mut first: Vec<u8> = std::iter::from_fn(|| queue.pop()).collect();
+        // two clock edges go missing halfway through the first frame
+        let half = first.len() / 2;
+        first.drain(half..half + 4);
+        queue.push(Frame::from_transmission(transmission));
+        for nibble in first.into_iter().chain(std::iter::from_fn(|| queue.pop())) {
+            a.send(nibble).unwrap();
+        }
+
+        bob.step(Instant::now()).unwrap();
+        assert_eq!(bob.completed(), Some(&data[..]));
+    }
+
+    #[test]
+    fn silent_peer_is_probed_until_retries_run_out() {
+        let clock = VirtualClock::new();
+        // nobody listens on the other end
+        let (a, _b) = loopback();
+        let mut alice = Peer::new(b"anyone there?", a);
+        let start = clock.now();
+
+        let mut failed = None;
+        for _ in 0..100_000 {
+            if let Err(e) = alice.step(clock.now()) {
+                failed = Some(e);
+                break;
+            }
+            clock.advance(PERIOD);
+        }
+        assert_eq!(failed, Some(RetriesExhausted { retries: 3 }));
+        // the data frame and one probe per timeout before giving up
+        assert_eq!(alice.frames_sent, 1 + 3);
+        // timeouts of 500, 1000, 2000 and 4000ms, plus the time on the wire
+        let on_wire = PERIOD * alice.nibbles_sent as u32;
+        let elapsed = clock.now() - start;
+        // give or take a step per frame
+        let slack = PERIOD * alice.frames_sent as u32;
+        let expected = Duration::from_millis(7500) + on_wire;
+        assert!(elapsed.abs_diff(expected) <= slack, "{elapsed:?}");
+    }
+
+    #[test]
+    fn timeout_waits_for_virtual_time() {
+        let clock = VirtualClock::new();
+        let (a, _b) = loopback();
+        let mut alice = Peer::new(b"x", a);
+        while !alice.scheduler.queue().is_empty() {
+            alice.step(clock.now()).unwrap();
+            clock.advance(PERIOD);
+        }
+        alice.step(clock.now()).unwrap();
+        let armed = alice.frames_sent;
+
+        // nothing happens however often it is polled while time stands still
+        for _ in 0..1000 {
+            alice.step(clock.now()).unwrap();
+        }
+        assert_eq!(alice.frames_sent, armed);
+
+        clock.advance(arq().initial_rto);
+        alice.step(clock.now()).unwrap();
+        assert_eq!(alice.frames_sent, armed + 1);
+    }
+
+    #[test]
+    fn chattering_peer_does_not_hold_off_the_timeout() {
+        let clock = VirtualClock::new();
+        let (a, mut b) = loopback();
+        let mut alice = Peer::new(b"x", a);
+        while !alice.scheduler.queue().is_empty() {
+            alice.step(clock.now()).unwrap();
+            clock.advance(PERIOD);
+        }
+        alice.step(clock.now()).unwrap();
+        let armed = alice.frames_sent;
+
+        // clock edges without a frame keep arriving, nothing answers our data
+        let rto = arq().initial_rto;
+        let steps = rto.as_millis() / PERIOD.as_millis();
+        for step in 0..steps {
+            b.send(if step % 2 == 0 { 0b1000 } else { 0 }).unwrap();
+            clock.advance(PERIOD);
+            alice.step(clock.now()).unwrap();
+        }
+        assert_eq!(alice.frames_sent, armed + 1);
+        assert_eq!(alice.session.ack_rto.retries(), 1);
+        assert_eq!(alice.session.ack_rto.srtt(), None);
+    }
+}
@@ -2,10 +2,15 @@ use std::{io, io::Read};
 
 use ansi_term::Color::{Blue, Green, Red, Yellow};
 
-use crate::{nibble, protocol::Packet};
-
-/// input 3 raw bytes, get 2 decoded bytes
-pub fn nibbles_to_bytes(nibbles: [u8; 3]) -> Vec<(u8, bool)> {
+use crate::{
+    controls::{Symbol, UnknownControl},
+    fec::Fec,
+    nibble,
+    protocol::Packet,
+};
+
+/// input 3 raw bytes, get 2 decoded symbols, a control flag on an unknown code is an error
+pub fn nibbles_to_symbols(nibbles: [u8; 3]) -> [Result<Symbol, UnknownControl>; 2] {
     let mut first_byte = (nibble!(nibbles[0]).0 & 0b0111) << 5;
     first_byte |= (nibble!(nibbles[0]).1 & 0b0111) << 2;
     first_byte |= (nibble!(nibbles[1]).0 & 0b0110) >> 1;
@@ -15,7 +20,25 @@ pub fn nibbles_to_bytes(nibbles: [u8; 3]) -> Vec<(u8, bool)> {
 
     let is_control_one: bool = nibble!(nibbles[1]).0 & 0b1 == 1;
     let is_control_two: bool = nibble!(nibbles[2]).1 & 0b1 == 1;
-    vec![(first_byte, is_control_one), (second_byte, is_control_two)]
+    [
+        Symbol::from_wire(first_byte, is_control_one),
+        Symbol::from_wire(second_byte, is_control_two),
+    ]
+}
+
+/// Packs clocked nibbles two to a byte, the last one padded with zeros
+pub fn squash_nibbles(nibbles: &[u8]) -> Vec<u8> {
+    let mut squashed_data: Vec<u8> = Vec::new();
+    for x in nibbles.chunks(2) {
+        let mut res: u8 = 0b0;
+        res |= x[0] << 4;
+        let second_nibble = x.get(1);
+        if let Some(nibble) = second_nibble {
+            res |= nibble;
+        }
+        squashed_data.push(res);
+    }
+    squashed_data
 }
 
 pub fn read_stdin_as_vec_u8() -> io::Result<Vec<u8>> {
@@ -24,14 +47,15 @@ pub fn read_stdin_as_vec_u8() -> io::Result<Vec<u8>> {
     Ok(buffer)
 }
 
-pub fn make_transmission(data: Vec<Vec<u8>>) -> Vec<Packet> {
+/// Packets with IDs from 1 on, `None` if a chunk is too long for a packet
+pub fn make_transmission(data: Vec<Vec<u8>>, fec: &dyn Fec) -> Option<Vec<Packet>> {
     let mut id = 0;
     let mut packets = Vec::new();
     for packet in data {
         id += 1;
-        packets.push(Packet::new(packet, id));
+        packets.push(Packet::with_fec(packet, id, fec)?);
     }
-    packets
+    Some(packets)
 }
 
 pub fn split_u16(bytes: u16) -> [u8; 2] {
@@ -40,6 +64,19 @@ pub fn split_u16(bytes: u16) -> [u8; 2] {
     [high_byte, low_byte]
 }
 
+/// CRC-8 (polynomial 0x07), checks packet headers before their length is trusted
+pub fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                crc << 1 ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
 pub fn chunk_data(data: Vec<u8>, size: usize) -> Vec<Vec<u8>> {
     let chunks: Vec<Vec<u8>> = data
         .chunks(size)
@@ -69,6 +106,14 @@ pub fn slice_data(received: Vec<u8>, start: usize, end: usize) -> Vec<u8> {
         .to_vec()
 }
 
+/// checks if the nibbles start with an SOT
+pub fn is_sot(nibbles: &[u8]) -> bool {
+    // there are 2 theoretically possible combinations of SOT (main difference being clocked)
+    (nibbles[0] == 0b0 || nibbles[0] == 0b1000)
+        && (nibbles[1] == 0b1 || nibbles[1] == 0b1001)
+        && (nibbles[2] == 0b111 || nibbles[2] == 0b1111)
+}
+
 /// checks if the data has a start and end
 pub fn start_and_end(p0: &Vec<u8>) -> Option<(usize, usize)> {
     let mut start_found = false;
@@ -76,15 +121,7 @@ pub fn start_and_end(p0: &Vec<u8>) -> Option<(usize, usize)> {
     let mut end_found = false;
     let mut end_index = 0;
     for i in 0..p0.len() - 2 {
-        let nibble_0 = p0[i];
-        let nibble_1 = p0[i + 1];
-        let nibble_2 = p0[i + 2];
-        // there are 2 theoretically possible combinations of SOT (main difference being clocked)
-        if (nibble_0 == 0b0 || nibble_0 == 0b1000)
-            && (nibble_1 == 0b0 || nibble_1 == 0b1001)
-            && (nibble_2 == 0b111 || nibble_2 == 0b1111)
-            && !start_found
-        {
+        if is_sot(&p0[i..]) && !start_found {
             // found SOT
             start_found = true;
             //eprint!("{} {}", Yellow.paint("start_found".to_string()), i);
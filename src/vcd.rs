@@ -0,0 +1,171 @@
+//! Value change dump of the wire for GTKWave or PulseView, like a logic analyzer trace.
+//!
+//! Every trace becomes a scope with the lines CLK and D0–D2. Annotated traces get two more
+//! signals: `frame`, high from SOT to EOT (or over a standalone NAK), and `control`, the code
+//! of the control byte on the wire while it is sent, 0 otherwise.
+
+use std::io::{self, Write};
+use std::time::Duration;
+
+use crate::{
+    capture::{Direction, Record},
+    controls::Symbol,
+    dissect::{clock_edges, edge_positions, frames},
+    encoder::unclock_symbol,
+};
+
+/// Nibbles of one direction with the time each one was put on the lines
+pub struct Trace {
+    pub name: String,
+    pub samples: Vec<(Duration, u8)>,
+}
+
+impl Trace {
+    /// Nibbles sent one every `delay`, e.g. a drained [`SendQueue`](crate::encoder::SendQueue)
+    /// or the output of `ready_for_send` with `CLK_DELAY`
+    pub fn uniform(name: &str, nibbles: &[u8], delay: Duration) -> Self {
+        Self {
+            name: name.to_string(),
+            samples: (0..)
+                .map(|i| delay * i)
+                .zip(nibbles.iter().map(|nibble| nibble & 0xF))
+                .collect(),
+        }
+    }
+
+    /// One direction of a capture with its recorded timing
+    pub fn from_records(name: &str, records: &[Record], direction: Direction) -> Self {
+        Self {
+            name: name.to_string(),
+            samples: records
+                .iter()
+                .filter(|record| record.direction == direction)
+                .map(|record| (record.at, record.nibble))
+                .collect(),
+        }
+    }
+
+    /// Changes of the `frame` and `control` signals, found the way `v7 decode` splits
+    /// a capture into frames and standalone NAKs
+    fn annotations(&self) -> Vec<(Duration, Annotation)> {
+        let samples: Vec<u8> = self.samples.iter().map(|&(_, nibble)| nibble).collect();
+        let edges = edge_positions(&samples);
+        let end_of_trace = self
+            .samples
+            .last()
+            .map_or(Duration::ZERO, |sample| sample.0);
+        // time of a clocked nibble
+        let time = |edge: usize| edges.get(edge).map_or(end_of_trace, |&i| self.samples[i].0);
+        let nibbles = clock_edges(&samples);
+
+        let mut changes = Vec::new();
+        for frame in frames(&nibbles) {
+            changes.push((time(frame.start), Annotation::Frame(true)));
+            for (i, symbol) in nibbles[frame.clone()].chunks_exact(3).enumerate() {
+                if let Ok(Symbol::Control(control)) = unclock_symbol(symbol) {
+                    let from = frame.start + i * 3;
+                    changes.push((time(from), Annotation::Control(control.code())));
+                    changes.push((time(from + 3), Annotation::Control(0)));
+                }
+            }
+            changes.push((time(frame.end), Annotation::Frame(false)));
+        }
+        changes
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Annotation {
+    Frame(bool),
+    Control(u8),
+}
+
+/// Identifier of the `n`th signal, printable ASCII as the format wants
+fn identifier(n: usize) -> String {
+    let mut id = String::new();
+    let mut n = n;
+    loop {
+        id.push((b'!' + (n % 94) as u8) as char);
+        n /= 94;
+        if n == 0 {
+            return id;
+        }
+        n -= 1;
+    }
+}
+
+/// Writes `traces` as VCD with a resolution of a microsecond,
+/// with `annotate` every trace also gets the `frame` and `control` signals
+pub fn write_vcd(out: &mut dyn Write, traces: &[Trace], annotate: bool) -> io::Result<()> {
+    const LINES: [&str; 4] = ["D0", "D1", "D2", "CLK"];
+    let signals = if annotate { 6 } else { 4 };
+
+    writeln!(out, "$version v7 {} $end", env!("CARGO_PKG_VERSION"))?;
+    writeln!(out, "$timescale 1us $end")?;
+    for (t, trace) in traces.iter().enumerate() {
+        writeln!(out, "$scope module {} $end", trace.name)?;
+        for (line, name) in LINES.iter().enumerate() {
+            writeln!(
+                out,
+                "$var wire 1 {} {name} $end",
+                identifier(t * signals + line)
+            )?;
+        }
+        if annotate {
+            writeln!(
+                out,
+                "$var wire 1 {} frame $end",
+                identifier(t * signals + 4)
+            )?;
+            writeln!(
+                out,
+                "$var wire 8 {} control $end",
+                identifier(t * signals + 5)
+            )?;
+        }
+        writeln!(out, "$upscope $end")?;
+    }
+    writeln!(out, "$enddefinitions $end")?;
+
+    // (time, signal, value) of every change, in time order
+    let mut changes: Vec<(Duration, usize, u8)> = Vec::new();
+    for (t, trace) in traces.iter().enumerate() {
+        let mut last = None;
+        for &(at, nibble) in &trace.samples {
+            for line in 0..4 {
+                let bit = nibble >> line & 1;
+                if last.is_none_or(|last: u8| last >> line & 1 != bit) {
+                    changes.push((at, t * signals + line, bit));
+                }
+            }
+            last = Some(nibble);
+        }
+        if annotate {
+            changes.push((Duration::ZERO, t * signals + 4, 0));
+            changes.push((Duration::ZERO, t * signals + 5, 0));
+            for (at, annotation) in trace.annotations() {
+                changes.push(match annotation {
+                    Annotation::Frame(high) => (at, t * signals + 4, u8::from(high)),
+                    Annotation::Control(code) => (at, t * signals + 5, code),
+                });
+            }
+        }
+    }
+    // stable: a control byte ending and the next one starting at the same time keep their order
+    changes.sort_by_key(|&(at, _, _)| at);
+
+    let mut now = None;
+    for (at, signal, value) in changes {
+        let micros = at.as_micros();
+        if now != Some(micros) {
+            writeln!(out, "#{micros}")?;
+            now = Some(micros);
+        }
+        if annotate && signal % signals == 5 {
+            writeln!(out, "b{value:08b} {}", identifier(signal))?;
+        } else {
+            writeln!(out, "{value}{}", identifier(signal))?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,131 @@
+//! Timing diagram of the four lines in the terminal, for benches without a logic analyzer.
+//!
+//! Every nibble is two columns wide. CLK is colored like `print_colored_byte` does, high green
+//! and low red. Below the lines the symbols of frames and standalone NAKs are aligned with the
+//! nibbles they were sent in: data bytes as hex, control bytes by name.
+
+use std::fmt::Write;
+
+use ansi_term::Color::{Green, Red, Yellow};
+
+use crate::{
+    controls::{Symbol, UnknownControl},
+    dissect::{clock_edges, edge_positions, frames},
+    encoder::unclock_symbol,
+};
+
+/// Columns per nibble
+const COLUMNS: usize = 2;
+
+struct Label {
+    /// Nibbles the symbol spans on the wire
+    from: usize,
+    to: usize,
+    symbol: Result<Symbol, UnknownControl>,
+}
+
+pub struct Waveform {
+    nibbles: Vec<u8>,
+    labels: Vec<Label>,
+}
+
+impl Waveform {
+    /// Nibbles as they were on the lines, either every one sent or only the changes
+    /// the Arduino reports
+    pub fn new(nibbles: &[u8]) -> Self {
+        let edges = edge_positions(nibbles);
+        let clocked = clock_edges(nibbles);
+        let wire = |edge: usize| edges.get(edge).copied().unwrap_or(nibbles.len());
+
+        let mut labels = Vec::new();
+        for frame in frames(&clocked) {
+            for (i, symbol) in clocked[frame.clone()].chunks_exact(3).enumerate() {
+                let at = frame.start + i * 3;
+                labels.push(Label {
+                    from: wire(at),
+                    to: wire(at + 3),
+                    symbol: unclock_symbol(symbol),
+                });
+            }
+        }
+        Self {
+            nibbles: nibbles.to_vec(),
+            labels,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nibbles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nibbles.is_empty()
+    }
+
+    /// Nibbles `from..from + width` as lines CLK, D2, D1, D0 and the symbols below
+    pub fn render(&self, from: usize, width: usize) -> String {
+        let to = (from + width).min(self.nibbles.len());
+        let from = from.min(to);
+        let mut out = String::new();
+
+        for (name, bit) in [("CLK", 3), ("D2", 2), ("D1", 1), ("D0", 0)] {
+            let _ = write!(out, "{name:<4}");
+            for i in from..to {
+                let level = self.nibbles[i] >> bit & 1;
+                let before = i
+                    .checked_sub(1)
+                    .map_or(level, |before| self.nibbles[before] >> bit & 1);
+                let edge = match (before, level) {
+                    (0, 1) => '╱',
+                    (1, 0) => '╲',
+                    _ => line(level),
+                };
+                let cell: String = std::iter::once(edge)
+                    .chain(std::iter::repeat_n(line(level), COLUMNS - 1))
+                    .collect();
+                match (bit, level) {
+                    (3, 1) => out.push_str(&Green.paint(cell).to_string()),
+                    (3, _) => out.push_str(&Red.paint(cell).to_string()),
+                    _ => out.push_str(&cell),
+                }
+            }
+            out.push('\n');
+        }
+
+        let _ = write!(out, "{:<4}", "");
+        let mut column = from;
+        for label in self
+            .labels
+            .iter()
+            .filter(|label| label.to > from && label.from < to)
+        {
+            let start = label.from.max(from);
+            out.push_str(&" ".repeat((start - column) * COLUMNS));
+            let end = label.to.min(to);
+            // the span minus a column to keep neighbouring symbols apart
+            let space = ((end - start) * COLUMNS).saturating_sub(1);
+            let text = match label.symbol {
+                Ok(Symbol::Data(byte)) => format!("{byte:02x}"),
+                Ok(Symbol::Control(control)) => control.to_string(),
+                Err(UnknownControl(byte)) => format!("?{byte:02x}"),
+            };
+            let text: String = format!("{text:<space$}").chars().take(space).collect();
+            match label.symbol {
+                Ok(Symbol::Data(_)) => out.push_str(&text),
+                Ok(Symbol::Control(_)) => out.push_str(&Yellow.paint(text).to_string()),
+                Err(_) => out.push_str(&Red.paint(text).to_string()),
+            }
+            out.push_str(&" ".repeat((end - start) * COLUMNS - space));
+            column = end;
+        }
+        out.push('\n');
+        out
+    }
+}
+
+fn line(level: u8) -> char {
+    match level {
+        1 => '▔',
+        _ => '▁',
+    }
+}